@@ -7,6 +7,7 @@ use tokio::sync::broadcast::Sender;
 use crate::clients::proxy_client::ProxyClient;
 use crate::clients::websocket_proxy_client::WebsocketProxyClient;
 use crate::config::app_config::AppConfig;
+use crate::handlers::upscale::CachedUpscale;
 use crate::tags_provider::UpscaleTagChecker;
 use crate::upscaler::upscale_actor::UpscaleSupervisorActor;
 
@@ -16,7 +17,7 @@ pub struct AppState {
     pub upscaler: ActorRef<UpscaleSupervisorActor>,
     pub proxy_client: Arc<ProxyClient>,
     pub websocket_proxy_client: Arc<WebsocketProxyClient>,
-    pub upscale_call_history_cache: Arc<Cache<String, ()>>,
+    pub upscaled_cache: Arc<Cache<String, CachedUpscale>>,
     pub upscale_tag_checker: Arc<UpscaleTagChecker>,
     pub shutdown_tx: Sender<()>,
 }
\ No newline at end of file
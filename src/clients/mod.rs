@@ -2,3 +2,4 @@ pub mod komga_client;
 pub mod kavita_client;
 pub mod proxy_client;
 pub mod websocket_proxy_client;
+pub mod upstream_tls;
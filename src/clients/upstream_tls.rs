@@ -0,0 +1,109 @@
+use std::fs;
+
+use log::warn;
+use native_tls::{Certificate, Identity, TlsConnector};
+use tokio_tungstenite::Connector;
+
+use crate::config::app_config::UpstreamTlsConfig;
+
+// tls settings shared by http and websocket upstream clients
+pub struct UpstreamTls {
+    ca_certs: Vec<Vec<u8>>,
+    identity: Option<(Vec<u8>, Vec<u8>)>,
+    insecure: bool,
+}
+
+impl UpstreamTls {
+    pub fn load(config: &UpstreamTlsConfig) -> Result<Self, String> {
+        let ca_certs = config.ca_certs.iter()
+            .map(|path| fs::read(path).map_err(|err| format!("can't read ca certificate {}: {}", path, err)))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (path, pem) in config.ca_certs.iter().zip(&ca_certs) {
+            if pem_certificates(pem).is_empty() {
+                return Err(format!("no pem certificates found in {}", path));
+            }
+        }
+
+        let identity = match (&config.client_cert, &config.client_key) {
+            (Some(cert_path), Some(key_path)) => {
+                let cert = fs::read(cert_path).map_err(|err| format!("can't read client certificate {}: {}", cert_path, err))?;
+                let key = fs::read(key_path).map_err(|err| format!("can't read client key {}: {}", key_path, err))?;
+                Some((cert, key))
+            }
+            (None, None) => None,
+            _ => return Err("both client_cert and client_key must be set".to_string())
+        };
+
+        if config.insecure {
+            warn!("upstream certificate verification is disabled");
+        }
+
+        Ok(Self { ca_certs, identity, insecure: config.insecure })
+    }
+
+    pub fn apply(&self, builder: reqwest::ClientBuilder) -> Result<reqwest::ClientBuilder, String> {
+        let mut builder = builder.danger_accept_invalid_certs(self.insecure);
+        for pem in &self.ca_certs {
+            for certificate in reqwest::Certificate::from_pem_bundle(pem).map_err(|err| err.to_string())? {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        if let Some((cert, key)) = &self.identity {
+            let identity = reqwest::Identity::from_pkcs8_pem(cert, key).map_err(|err| err.to_string())?;
+            builder = builder.identity(identity);
+        }
+        Ok(builder)
+    }
+
+    pub fn websocket_connector(&self) -> Result<Connector, String> {
+        let mut builder = TlsConnector::builder();
+        builder.danger_accept_invalid_certs(self.insecure);
+        for pem in self.ca_certs.iter().flat_map(|pem| pem_certificates(pem)) {
+            builder.add_root_certificate(Certificate::from_pem(pem).map_err(|err| err.to_string())?);
+        }
+        if let Some((cert, key)) = &self.identity {
+            builder.identity(Identity::from_pkcs8(cert, key).map_err(|err| err.to_string())?);
+        }
+        let connector = builder.build().map_err(|err| err.to_string())?;
+        Ok(Connector::NativeTls(connector))
+    }
+}
+
+// native-tls parses only the first certificate of pem file. bundles are split into separate certificates
+fn pem_certificates(pem: &[u8]) -> Vec<&[u8]> {
+    const BEGIN: &[u8] = b"-----BEGIN CERTIFICATE-----";
+    const END: &[u8] = b"-----END CERTIFICATE-----";
+
+    let find = |haystack: &[u8], needle: &[u8]| haystack.windows(needle.len()).position(|window| window == needle);
+    let mut certificates = Vec::new();
+    let mut rest = pem;
+    while let Some(start) = find(rest, BEGIN) {
+        let end = match find(&rest[start..], END) {
+            Some(end) => start + end + END.len(),
+            None => break
+        };
+        certificates.push(&rest[start..end]);
+        rest = &rest[end..];
+    }
+    certificates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_pem_bundle() {
+        let bundle = b"subject=first\n-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n\
+            -----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----\n";
+        let certificates = pem_certificates(bundle);
+        assert_eq!(certificates.len(), 2);
+        assert_eq!(certificates[0], b"-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----");
+        assert_eq!(certificates[1], b"-----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----");
+    }
+
+    #[test]
+    fn ignores_unterminated_certificate() {
+        assert!(pem_certificates(b"-----BEGIN CERTIFICATE-----\nAAAA\n").is_empty());
+    }
+}
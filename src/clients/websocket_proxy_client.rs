@@ -1,19 +1,32 @@
+use hyper::HeaderMap;
+use hyper::header::{AUTHORIZATION, COOKIE, ORIGIN, SEC_WEBSOCKET_PROTOCOL};
 use tokio::net::TcpStream;
-use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::{connect_async_tls_with_config, Connector, MaybeTlsStream, WebSocketStream};
 use tokio_tungstenite::tungstenite::Error;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
 use tokio_tungstenite::tungstenite::handshake::client::Response;
 
+// handshake headers sent to upstream. everything else is generated by tungstenite
+const FORWARDED_HEADERS: [hyper::header::HeaderName; 4] = [AUTHORIZATION, COOKIE, ORIGIN, SEC_WEBSOCKET_PROTOCOL];
+
 pub struct WebsocketProxyClient {
     base_url: String,
+    connector: Connector,
 }
 
 impl WebsocketProxyClient {
-    pub fn new(base_url: String) -> Self {
-        Self { base_url }
+    pub fn new(base_url: String, connector: Connector) -> Self {
+        Self { base_url, connector }
     }
 
-    pub async fn spawn_client(&self, path_and_query: &str) -> Result<(WebSocketStream<MaybeTlsStream<TcpStream>>, Response), Error> {
-        let url = format!("{}{}", self.base_url, path_and_query);
-        Ok(connect_async(url).await?)
+    pub async fn spawn_client(&self, path_and_query: &str, headers: &HeaderMap) -> Result<(WebSocketStream<MaybeTlsStream<TcpStream>>, Response), Error> {
+        let mut request = format!("{}{}", self.base_url, path_and_query).into_client_request()?;
+        for name in FORWARDED_HEADERS {
+            for value in headers.get_all(&name) {
+                request.headers_mut().append(name.clone(), value.clone());
+            }
+        }
+
+        connect_async_tls_with_config(request, None, Some(self.connector.clone())).await
     }
-}
\ No newline at end of file
+}
@@ -11,31 +11,152 @@ use waifu2x_ncnn_vulkan_rs::ModelType;
 #[allow(unused)]
 pub struct AppConfig {
     pub port: u16,
+    pub bind: Vec<String>,
+    pub unix_socket_mode: String,
+    pub tls: TlsConfig,
     pub upstream_url: String,
+    pub upstream_tls: UpstreamTlsConfig,
+    pub websocket: WebsocketConfig,
     pub upscale: bool,
     pub return_format: Format,
+    pub negotiate_formats: Vec<Format>,
+    pub encoder: EncoderConfig,
     pub size_threshold_enabled: bool,
     pub size_threshold: u32,
     pub size_threshold_png: u32,
     pub upscaler: EnabledUpscaler,
     pub waifu2x: Waifu2xConfig,
     pub realcugan: RealCuganConfig,
+    pub dejpeg: DejpegConfig,
+    pub chain: Vec<ChainStage>,
+    pub profiles: Vec<UpscaleProfile>,
+    pub noise_detection: NoiseDetectionConfig,
+    pub animation: AnimationConfig,
+    pub tiling: TilingConfig,
+    pub spreads: SpreadConfig,
+    pub preprocess: PreprocessConfig,
+    pub postprocess: Vec<PostprocessStep>,
+    pub quality_guard: QualityGuardConfig,
+    pub limits: LimitsConfig,
+    pub cache_headers: CacheHeadersConfig,
+    pub compress_uncompressed_images: bool,
+    pub upscaled_cache_mb: u64,
     pub upscale_tag: Option<String>,
     pub allow_config_updates: bool,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    pub port: u16,
+    pub bind: Vec<String>,
+    pub cert_path: String,
+    pub key_path: String,
+    pub reload_interval: u64,
+    pub keep_http: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UpstreamTlsConfig {
+    pub ca_certs: Vec<String>,
+    pub insecure: bool,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WebsocketConfig {
+    pub ping_interval: u64,
+    pub idle_timeout: u64,
+    pub connect_timeout: u64,
+}
+
 #[derive(Serialize, Deserialize, Debug, Copy, Clone)]
 pub enum Format {
     Png,
     Jpeg,
     WebP,
+    Avif,
+    Jxl,
     Original,
+    Negotiate,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EncoderConfig {
+    pub jpeg: JpegEncoderConfig,
+    pub webp: WebPEncoderConfig,
+    pub png: PngEncoderConfig,
+    pub avif: AvifEncoderConfig,
+    pub jxl: JxlEncoderConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JpegEncoderConfig {
+    pub quality: u8,
+    pub chroma_subsampling: ChromaSubsampling,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub enum ChromaSubsampling {
+    Yuv444,
+    Yuv422,
+    Yuv420,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WebPEncoderConfig {
+    pub lossless: bool,
+    pub quality: u8,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PngEncoderConfig {
+    pub compression: PngCompression,
+    pub optimize: bool,
+    pub optimize_level: u8,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub enum PngCompression {
+    Fast,
+    Default,
+    Best,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AvifEncoderConfig {
+    pub quality: u8,
+    pub speed: u8,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JxlEncoderConfig {
+    pub distance: f32,
+    pub effort: u8,
+    pub lossless: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum EnabledUpscaler {
     Waifu2x,
     Realcugan,
+    Chain,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum ChainStage {
+    Waifu2x { noise: i32, scale: u32 },
+    Realcugan { noise: i32, scale: u32 },
+    Dejpeg { noise: i32 },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UpscaleProfile {
+    pub name: String,
+    pub tag: String,
+    pub chain: Vec<ChainStage>,
+    pub preprocess: Option<PreprocessConfig>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -83,6 +204,99 @@ pub struct RealCuganConfig {
     pub models_path: String,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NoiseDetectionConfig {
+    pub enabled: bool,
+    pub lossless_noise: i32,
+    pub jpeg_quality_noise: Vec<JpegQualityNoise>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JpegQualityNoise {
+    pub min_quality: u32,
+    pub noise: i32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AnimationConfig {
+    pub mode: AnimationMode,
+    pub max_frames: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub enum AnimationMode {
+    Passthrough,
+    Upscale,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TilingConfig {
+    pub enabled: bool,
+    pub band_height: u32,
+    pub overlap: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SpreadConfig {
+    pub enabled: bool,
+    pub min_aspect_ratio: f32,
+    pub gutter_check: bool,
+    pub split_upscale: bool,
+    pub max_half_width: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PreprocessConfig {
+    pub trim_margins: bool,
+    pub trim_tolerance: u8,
+    pub descreen: bool,
+    pub descreen_sigma: f32,
+    pub deskew: bool,
+    pub deskew_max_angle: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum PostprocessStep {
+    Sharpen { sigma: f32, threshold: i32 },
+    Levels { black_point: u8, white_point: u8 },
+    Deband { radius: f32, threshold: u8 },
+    Downscale { scale: f32 },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QualityGuardConfig {
+    pub enabled: bool,
+    pub min_psnr: f64,
+    pub min_ssim: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LimitsConfig {
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_alloc_mb: u64,
+    pub max_output_pixels: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DejpegConfig {
+    #[serde(with = "ModelTypeDef")]
+    pub model: ModelType,
+    pub models_path: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CacheHeadersConfig {
+    pub upscaled: CacheHeaders,
+    pub passthrough: CacheHeaders,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CacheHeaders {
+    pub cache_control: Option<String>,
+    pub expires: Option<u64>,
+}
+
 impl AppConfig {
     pub fn new() -> Result<Self, ConfigError> {
         let config_dir = AppConfig::get_config_directory();
@@ -109,6 +323,109 @@ impl AppConfig {
         realcugan_config.insert("num_threads".to_string(), "2");
         realcugan_config.insert("models_path".to_string(), models_default_dir.to_str().unwrap());
 
+        let mut dejpeg_config = config::Map::new();
+        dejpeg_config.insert("model".to_string(), "Cunet");
+        dejpeg_config.insert("models_path".to_string(), models_default_dir.to_str().unwrap());
+
+        let cert_default_path = config_dir.join("cert.pem");
+        let key_default_path = config_dir.join("key.pem");
+        let mut tls_config = config::Map::new();
+        tls_config.insert("enabled".to_string(), config::Value::from("false"));
+        tls_config.insert("port".to_string(), config::Value::from("3443"));
+        tls_config.insert("bind".to_string(), config::Value::from(Vec::<config::Value>::new()));
+        tls_config.insert("cert_path".to_string(), config::Value::from(cert_default_path.to_str().unwrap()));
+        tls_config.insert("key_path".to_string(), config::Value::from(key_default_path.to_str().unwrap()));
+        tls_config.insert("reload_interval".to_string(), config::Value::from("60"));
+        tls_config.insert("keep_http".to_string(), config::Value::from("true"));
+
+        let mut upstream_tls_config = config::Map::new();
+        upstream_tls_config.insert("ca_certs".to_string(), config::Value::from(Vec::<config::Value>::new()));
+        upstream_tls_config.insert("insecure".to_string(), config::Value::from(false));
+
+        let mut websocket_config = config::Map::new();
+        websocket_config.insert("ping_interval".to_string(), "30");
+        websocket_config.insert("idle_timeout".to_string(), "300");
+        websocket_config.insert("connect_timeout".to_string(), "10");
+
+        let mut jpeg_encoder_config = config::Map::new();
+        jpeg_encoder_config.insert("quality".to_string(), "90");
+        jpeg_encoder_config.insert("chroma_subsampling".to_string(), "Yuv420");
+
+        let mut webp_encoder_config = config::Map::new();
+        webp_encoder_config.insert("lossless".to_string(), "false");
+        webp_encoder_config.insert("quality".to_string(), "90");
+
+        let mut png_encoder_config = config::Map::new();
+        png_encoder_config.insert("compression".to_string(), "Default");
+        png_encoder_config.insert("optimize".to_string(), "false");
+        png_encoder_config.insert("optimize_level".to_string(), "2");
+
+        let mut avif_encoder_config = config::Map::new();
+        avif_encoder_config.insert("quality".to_string(), "80");
+        avif_encoder_config.insert("speed".to_string(), "6");
+
+        let mut jxl_encoder_config = config::Map::new();
+        jxl_encoder_config.insert("distance".to_string(), "1.0");
+        jxl_encoder_config.insert("effort".to_string(), "7");
+        jxl_encoder_config.insert("lossless".to_string(), "false");
+
+        let mut encoder_config = config::Map::new();
+        encoder_config.insert("jpeg".to_string(), config::Value::from(jpeg_encoder_config));
+        encoder_config.insert("webp".to_string(), config::Value::from(webp_encoder_config));
+        encoder_config.insert("png".to_string(), config::Value::from(png_encoder_config));
+        encoder_config.insert("avif".to_string(), config::Value::from(avif_encoder_config));
+        encoder_config.insert("jxl".to_string(), config::Value::from(jxl_encoder_config));
+
+        let jpeg_quality_noise: Vec<config::Value> = [(90, 0), (80, 1), (60, 2), (0, 3)].iter()
+            .map(|(min_quality, noise)| {
+                let mut level = config::Map::new();
+                level.insert("min_quality".to_string(), config::Value::from(*min_quality));
+                level.insert("noise".to_string(), config::Value::from(*noise));
+                config::Value::from(level)
+            })
+            .collect();
+        let mut noise_detection_config = config::Map::new();
+        noise_detection_config.insert("enabled".to_string(), config::Value::from(false));
+        noise_detection_config.insert("lossless_noise".to_string(), config::Value::from(-1));
+        noise_detection_config.insert("jpeg_quality_noise".to_string(), config::Value::from(jpeg_quality_noise));
+
+        let mut animation_config = config::Map::new();
+        animation_config.insert("mode".to_string(), "Passthrough");
+        animation_config.insert("max_frames".to_string(), "100");
+
+        let mut tiling_config = config::Map::new();
+        tiling_config.insert("enabled".to_string(), "false");
+        tiling_config.insert("band_height".to_string(), "2000");
+        tiling_config.insert("overlap".to_string(), "32");
+
+        let mut spread_config = config::Map::new();
+        spread_config.insert("enabled".to_string(), "false");
+        spread_config.insert("min_aspect_ratio".to_string(), "1.2");
+        spread_config.insert("gutter_check".to_string(), "true");
+        spread_config.insert("split_upscale".to_string(), "true");
+
+        let mut preprocess_config = config::Map::new();
+        preprocess_config.insert("trim_margins".to_string(), "false");
+        preprocess_config.insert("trim_tolerance".to_string(), "10");
+        preprocess_config.insert("descreen".to_string(), "false");
+        preprocess_config.insert("descreen_sigma".to_string(), "0.8");
+        preprocess_config.insert("deskew".to_string(), "false");
+        preprocess_config.insert("deskew_max_angle".to_string(), "3.0");
+
+        let mut quality_guard_config = config::Map::new();
+        quality_guard_config.insert("enabled".to_string(), "false");
+        quality_guard_config.insert("min_psnr".to_string(), "20.0");
+        quality_guard_config.insert("min_ssim".to_string(), "0.5");
+
+        let mut limits_config = config::Map::new();
+        limits_config.insert("max_width".to_string(), "10000");
+        limits_config.insert("max_height".to_string(), "100000");
+        limits_config.insert("max_alloc_mb".to_string(), "1024");
+        limits_config.insert("max_output_pixels".to_string(), "250000000");
+
+        let mut cache_headers_config = config::Map::new();
+        cache_headers_config.insert("upscaled".to_string(), config::Value::from(config::Map::<String, config::Value>::new()));
+        cache_headers_config.insert("passthrough".to_string(), config::Value::from(config::Map::<String, config::Value>::new()));
 
         let mut config = Config::builder();
         if config_dir.join("config.yml").exists() {
@@ -117,14 +434,35 @@ impl AppConfig {
 
         config = config.add_source(Environment::with_prefix("kurp"))
             .set_default("port", "3030")?
+            .set_default("bind", Vec::<String>::new())?
+            .set_default("unix_socket_mode", "660")?
+            .set_default("tls", tls_config)?
             .set_default("upstream_url", "http://localhost:8080")?
+            .set_default("upstream_tls", upstream_tls_config)?
+            .set_default("websocket", websocket_config)?
             .set_default("upscale", true)?
             .set_default("return_format", "WebP")?
+            .set_default("negotiate_formats", vec!["WebP", "Jpeg"])?
+            .set_default("encoder", encoder_config)?
             .set_default("size_threshold_enabled", "true")?
             .set_default("size_threshold", "500")?
             .set_default("size_threshold_png", "1000")?
             .set_default("waifu2x", waifu2x_config)?
             .set_default("realcugan", realcugan_config)?
+            .set_default("dejpeg", dejpeg_config)?
+            .set_default("chain", Vec::<config::Value>::new())?
+            .set_default("profiles", Vec::<config::Value>::new())?
+            .set_default("noise_detection", noise_detection_config)?
+            .set_default("animation", animation_config)?
+            .set_default("tiling", tiling_config)?
+            .set_default("spreads", spread_config)?
+            .set_default("preprocess", preprocess_config)?
+            .set_default("postprocess", Vec::<config::Value>::new())?
+            .set_default("quality_guard", quality_guard_config)?
+            .set_default("limits", limits_config)?
+            .set_default("cache_headers", cache_headers_config)?
+            .set_default("compress_uncompressed_images", false)?
+            .set_default("upscaled_cache_mb", "128")?
             .set_default("upscaler", "Waifu2x")?
             .set_default("allow_config_updates", false)?;
 
@@ -0,0 +1,153 @@
+use axum::http::{HeaderMap, HeaderValue};
+
+use crate::config::app_config::{AppConfig, EncoderConfig, Format};
+use crate::upscaler::image_type::ImageType;
+
+pub struct FormatPreference {
+    accept: Vec<(String, f32)>,
+    save_data: bool,
+}
+
+impl FormatPreference {
+    pub fn from_headers(headers: &HeaderMap<HeaderValue>) -> Self {
+        let accept = headers.get_all("accept").iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(parse_media_range)
+            .collect();
+        let save_data = headers.get("save-data")
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim().eq_ignore_ascii_case("on"))
+            .unwrap_or(false);
+
+        Self { accept, save_data }
+    }
+
+    // identifies negotiated representation
+    pub fn cache_key(&self) -> String {
+        let accept: Vec<String> = self.accept.iter()
+            .map(|(range, q)| format!("{};q={}", range, q))
+            .collect();
+        format!("{}|{}", accept.join(","), self.save_data)
+    }
+
+    fn accepts(&self, image_type: ImageType) -> bool {
+        let mime_type = image_type.mime_type();
+        if let Some((_, q)) = self.accept.iter().find(|(range, _)| range == mime_type) {
+            return *q > 0.0;
+        }
+
+        // clients that send only wildcards are not guaranteed to support modern formats
+        match image_type {
+            ImageType::WebP | ImageType::Avif | ImageType::Jxl => false,
+            _ => self.accept.is_empty() || self.accept.iter()
+                .any(|(range, q)| (range == "image/*" || range == "*/*") && *q > 0.0)
+        }
+    }
+
+    fn select(&self, formats: &[Format], source: ImageType, encoder: &EncoderConfig) -> ImageType {
+        let mut candidates: Vec<ImageType> = formats.iter()
+            .filter_map(|format| to_image_type(*format, source))
+            .collect();
+        if self.save_data {
+            candidates.sort_by_key(|image_type| size_rank(*image_type, encoder));
+        }
+
+        // types outside of Accept are never sent, including source type. jpeg and png are the fallback
+        let fallback = match source.closest_encodable() {
+            ImageType::Jpeg => [ImageType::Jpeg, ImageType::Png],
+            _ => [ImageType::Png, ImageType::Jpeg],
+        };
+        candidates.into_iter()
+            .chain(fallback)
+            .find(|image_type| self.accepts(*image_type))
+            .unwrap_or(fallback[0])
+    }
+}
+
+pub fn output_type(config: &AppConfig, preference: &FormatPreference, source: ImageType) -> ImageType {
+    match config.return_format {
+        Format::Negotiate => preference.select(&config.negotiate_formats, source, &config.encoder),
+        format => to_image_type(format, source).unwrap_or(source.closest_encodable())
+    }
+}
+
+fn to_image_type(format: Format, source: ImageType) -> Option<ImageType> {
+    match format {
+        Format::Png => Some(ImageType::Png),
+        Format::Jpeg => Some(ImageType::Jpeg),
+        Format::WebP => Some(ImageType::WebP),
+        Format::Avif => Some(ImageType::Avif),
+        Format::Jxl => Some(ImageType::Jxl),
+        Format::Original => Some(source.closest_encodable()),
+        Format::Negotiate => None,
+    }
+}
+
+// lower is smaller. lossless encodings go after every lossy one
+fn size_rank(image_type: ImageType, encoder: &EncoderConfig) -> u32 {
+    match image_type {
+        ImageType::Avif => 0,
+        ImageType::Jxl if !encoder.jxl.lossless => 1,
+        ImageType::WebP if !encoder.webp.lossless => 2,
+        ImageType::Jpeg => 3,
+        ImageType::Jxl => 10,
+        ImageType::WebP => 11,
+        _ => 12,
+    }
+}
+
+fn parse_media_range(value: &str) -> Option<(String, f32)> {
+    let mut params = value.split(';');
+    let media_range = params.next()?.trim().to_ascii_lowercase();
+    if media_range.is_empty() { return None; }
+
+    let q = params
+        .filter_map(|param| param.trim().strip_prefix("q="))
+        .find_map(|q| q.trim().parse::<f32>().ok())
+        .unwrap_or(1.0);
+
+    Some((media_range, q))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preference(accept: &'static str) -> FormatPreference {
+        let mut headers = HeaderMap::new();
+        headers.insert("accept", HeaderValue::from_static(accept));
+        FormatPreference::from_headers(&headers)
+    }
+
+    #[test]
+    fn explicit_types_are_accepted() {
+        assert!(preference("image/webp,image/apng,*/*;q=0.8").accepts(ImageType::WebP));
+        assert!(preference("IMAGE/AVIF").accepts(ImageType::Avif));
+    }
+
+    #[test]
+    fn wildcards_accept_only_widely_supported_types() {
+        let preference = preference("image/*");
+        assert!(preference.accepts(ImageType::Jpeg));
+        assert!(preference.accepts(ImageType::Png));
+        assert!(!preference.accepts(ImageType::WebP));
+        assert!(FormatPreference::from_headers(&HeaderMap::new()).accepts(ImageType::Jpeg));
+    }
+
+    #[test]
+    fn zero_q_value_excludes_type() {
+        assert!(!preference("image/webp;q=0, image/*").accepts(ImageType::WebP));
+        assert!(!preference("image/png;q=0, */*").accepts(ImageType::Png));
+        assert!(!preference("*/*;q=0").accepts(ImageType::Jpeg));
+    }
+
+    #[test]
+    fn source_type_is_not_sent_outside_of_accept() {
+        let encoder = AppConfig::new().unwrap().encoder;
+        assert_eq!(preference("image/jpeg").select(&[Format::WebP], ImageType::WebP, &encoder), ImageType::Jpeg);
+        assert_eq!(preference("image/avif, image/*").select(&[Format::WebP], ImageType::WebP, &encoder), ImageType::Png);
+        assert_eq!(preference("image/avif, image/*").select(&[Format::WebP], ImageType::Jpeg, &encoder), ImageType::Jpeg);
+        assert_eq!(preference("image/webp").select(&[Format::WebP], ImageType::Png, &encoder), ImageType::WebP);
+    }
+}
@@ -0,0 +1,258 @@
+use axum::http::HeaderValue;
+use serde_derive::Serialize;
+
+use crate::config::app_config::{AnimationConfig, AppConfig, ChainStage, EnabledUpscaler, EncoderConfig, Format, LimitsConfig, NoiseDetectionConfig, PostprocessStep, PreprocessConfig, QualityGuardConfig, SpreadConfig, TilingConfig, UpscaleProfile};
+use crate::content_negotiation::FormatPreference;
+
+// settings that change upscaled output. listener, upstream and cache header settings are left out
+#[derive(Serialize)]
+struct OutputSettings<'a> {
+    upscale: bool,
+    return_format: &'a Format,
+    negotiate_formats: &'a Vec<Format>,
+    encoder: &'a EncoderConfig,
+    size_threshold_enabled: bool,
+    size_threshold: u32,
+    size_threshold_png: u32,
+    upscaler: &'a EnabledUpscaler,
+    waifu2x: Option<ModelSettings>,
+    realcugan: Option<ModelSettings>,
+    dejpeg: Option<String>,
+    chain: Option<&'a Vec<ChainStage>>,
+    profiles: &'a Vec<UpscaleProfile>,
+    noise_detection: &'a NoiseDetectionConfig,
+    animation: &'a AnimationConfig,
+    tiling: &'a TilingConfig,
+    spreads: &'a SpreadConfig,
+    preprocess: &'a PreprocessConfig,
+    postprocess: &'a Vec<PostprocessStep>,
+    quality_guard: &'a QualityGuardConfig,
+    limits: &'a LimitsConfig,
+    compress_uncompressed_images: bool,
+    format_preference: Option<String>,
+}
+
+// models of upscalers that are in use. gpu, threads, tile size and model paths don't change output
+#[derive(Serialize)]
+struct ModelSettings {
+    model: String,
+    noise: i32,
+    scale: u32,
+    tta_mode: bool,
+    sync_gap: Option<u32>,
+}
+
+// upscaled images are tagged with upstream etag, hash of settings and hash of profile that produced them.
+// "abc" becomes "abc-k<hash>" or "abc-k<hash>-p<profile hash>".
+// profile is resolved after upstream request so only settings hash is known when If-None-Match is translated
+pub fn settings_hash(config: &AppConfig, preference: &FormatPreference) -> String {
+    let settings = OutputSettings {
+        upscale: config.upscale,
+        return_format: &config.return_format,
+        negotiate_formats: &config.negotiate_formats,
+        encoder: &config.encoder,
+        size_threshold_enabled: config.size_threshold_enabled,
+        size_threshold: config.size_threshold,
+        size_threshold_png: config.size_threshold_png,
+        upscaler: &config.upscaler,
+        waifu2x: (matches!(config.upscaler, EnabledUpscaler::Waifu2x) || uses_stage(config, |stage| matches!(stage, ChainStage::Waifu2x { .. })))
+            .then(|| ModelSettings {
+                model: format!("{:?}", config.waifu2x.model),
+                noise: config.waifu2x.noise,
+                scale: config.waifu2x.scale,
+                tta_mode: config.waifu2x.tta_mode,
+                sync_gap: None,
+            }),
+        realcugan: (matches!(config.upscaler, EnabledUpscaler::Realcugan) || uses_stage(config, |stage| matches!(stage, ChainStage::Realcugan { .. })))
+            .then(|| ModelSettings {
+                model: format!("{:?}", config.realcugan.model),
+                noise: config.realcugan.noise,
+                scale: config.realcugan.scale,
+                tta_mode: config.realcugan.tta_mode,
+                sync_gap: Some(config.realcugan.sync_gap),
+            }),
+        dejpeg: uses_stage(config, |stage| matches!(stage, ChainStage::Dejpeg { .. }))
+            .then(|| format!("{:?} {}", config.dejpeg.model, config.waifu2x.tta_mode)),
+        chain: match config.upscaler {
+            EnabledUpscaler::Chain => Some(&config.chain),
+            _ => None
+        },
+        profiles: &config.profiles,
+        noise_detection: &config.noise_detection,
+        animation: &config.animation,
+        tiling: &config.tiling,
+        spreads: &config.spreads,
+        preprocess: &config.preprocess,
+        postprocess: &config.postprocess,
+        quality_guard: &config.quality_guard,
+        limits: &config.limits,
+        compress_uncompressed_images: config.compress_uncompressed_images,
+        format_preference: match config.return_format {
+            Format::Negotiate => Some(preference.cache_key()),
+            _ => None
+        },
+    };
+    let serialized = serde_json::to_vec(&settings).unwrap_or_default();
+    format!("{:016x}", fnv1a(&serialized))
+}
+
+// stage is part of active chain or of any profile
+fn uses_stage(config: &AppConfig, is_stage: impl Fn(&ChainStage) -> bool) -> bool {
+    let chain = match config.upscaler {
+        EnabledUpscaler::Chain => config.chain.as_slice(),
+        _ => &[]
+    };
+    chain.iter()
+        .chain(config.profiles.iter().flat_map(|profile| profile.chain.iter()))
+        .any(is_stage)
+}
+
+// hash must stay the same between builds. std hashers don't guarantee that
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+pub fn upscaled_etag(upstream_etag: &HeaderValue, settings_hash: &str, profile: Option<&str>) -> Option<HeaderValue> {
+    let (weak, opaque) = split_etag(upstream_etag.to_str().ok()?)?;
+    let profile_suffix = profile
+        .map(|profile| format!("-p{:016x}", fnv1a(profile.as_bytes())))
+        .unwrap_or_default();
+    HeaderValue::from_str(&format!("{}\"{}-k{}{}\"", weak, opaque, settings_hash, profile_suffix)).ok()
+}
+
+// keeps only etags produced with current settings and converts them back to upstream etags
+pub fn to_upstream_if_none_match(if_none_match: &HeaderValue, settings_hash: &str) -> Option<HeaderValue> {
+    let value = if_none_match.to_str().ok()?;
+    if value.trim() == "*" {
+        return Some(if_none_match.clone());
+    }
+
+    let upstream_etags: Vec<String> = value.split(',')
+        .filter_map(|etag| split_etag(etag.trim()))
+        .filter_map(|(weak, opaque)| upstream_opaque(opaque, settings_hash)
+            .map(|upstream| format!("{}\"{}\"", weak, upstream))
+        )
+        .collect();
+    if upstream_etags.is_empty() {
+        return None;
+    }
+    HeaderValue::from_str(&upstream_etags.join(", ")).ok()
+}
+
+fn upstream_opaque<'a>(opaque: &'a str, settings_hash: &str) -> Option<&'a str> {
+    let (upstream, profile_suffix) = opaque.rsplit_once(&format!("-k{}", settings_hash))?;
+    let is_profile_hash = |hash: &str| !hash.is_empty() && hash.chars().all(|c| c.is_ascii_hexdigit());
+    if profile_suffix.is_empty() || profile_suffix.strip_prefix("-p").is_some_and(is_profile_hash) {
+        Some(upstream)
+    } else {
+        None
+    }
+}
+
+// weak comparison as required for If-None-Match
+pub fn matches(if_none_match: Option<&HeaderValue>, etag: &HeaderValue) -> bool {
+    let (if_none_match, etag) = match (if_none_match.and_then(|value| value.to_str().ok()), etag.to_str().ok()) {
+        (Some(if_none_match), Some(etag)) => (if_none_match, etag),
+        _ => return false
+    };
+    if if_none_match.trim() == "*" {
+        return true;
+    }
+
+    let opaque = split_etag(etag).map(|(_, opaque)| opaque);
+    if_none_match.split(',')
+        .filter_map(|candidate| split_etag(candidate.trim()))
+        .any(|(_, candidate)| Some(candidate) == opaque)
+}
+
+fn split_etag(etag: &str) -> Option<(&str, &str)> {
+    let (weak, quoted) = match etag.strip_prefix("W/") {
+        Some(quoted) => ("W/", quoted),
+        None => ("", etag)
+    };
+    let opaque = quoted.strip_prefix('"')?.strip_suffix('"')?;
+    Some((weak, opaque))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(value: &'static str) -> HeaderValue {
+        HeaderValue::from_static(value)
+    }
+
+    #[test]
+    fn fnv1a_is_stable() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+    }
+
+    #[test]
+    fn settings_hash_ignores_settings_without_effect_on_output() {
+        let mut config = AppConfig::new().unwrap();
+        config.upscaler = EnabledUpscaler::Waifu2x;
+        let preference = FormatPreference::from_headers(&Default::default());
+        let hash = settings_hash(&config, &preference);
+
+        config.waifu2x.gpuid = 1;
+        config.waifu2x.num_threads = 8;
+        config.waifu2x.tile_size = 400;
+        config.waifu2x.models_path = "/other".to_string();
+        config.realcugan.noise = 3;
+        config.chain = vec![ChainStage::Dejpeg { noise: 3 }];
+        assert_eq!(settings_hash(&config, &preference), hash);
+
+        config.waifu2x.noise = 2;
+        assert_ne!(settings_hash(&config, &preference), hash);
+    }
+
+    #[test]
+    fn upscaled_etag_keeps_weakness() {
+        assert_eq!(upscaled_etag(&header("\"abc\""), "1234", None).unwrap(), "\"abc-k1234\"");
+        assert_eq!(upscaled_etag(&header("W/\"abc\""), "1234", None).unwrap(), "W/\"abc-k1234\"");
+        assert!(upscaled_etag(&header("abc"), "1234", None).is_none());
+    }
+
+    #[test]
+    fn upscaled_etag_includes_profile() {
+        let etag = upscaled_etag(&header("\"abc\""), "1234", Some("restore")).unwrap();
+        let etag = etag.to_str().unwrap();
+        assert!(etag.starts_with("\"abc-k1234-p"));
+        assert_ne!(Some(etag), upscaled_etag(&header("\"abc\""), "1234", Some("other")).unwrap().to_str().ok());
+    }
+
+    #[test]
+    fn if_none_match_is_translated_to_upstream_etags() {
+        let upscaled = upscaled_etag(&header("W/\"ghi\""), "1234", Some("restore")).unwrap();
+        let if_none_match = HeaderValue::from_str(&format!("\"abc-k1234\", \"def-k5678\", \"plain\", {}", upscaled.to_str().unwrap())).unwrap();
+
+        assert_eq!(to_upstream_if_none_match(&if_none_match, "1234").unwrap(), "\"abc\", W/\"ghi\"");
+    }
+
+    #[test]
+    fn if_none_match_without_current_settings_is_dropped() {
+        assert!(to_upstream_if_none_match(&header("\"abc-k5678\", \"plain\""), "1234").is_none());
+        assert!(to_upstream_if_none_match(&header("\"abc-k1234-pxyz\""), "1234").is_none());
+        assert_eq!(to_upstream_if_none_match(&header("*"), "1234").unwrap(), "*");
+    }
+
+    #[test]
+    fn revalidation_requires_current_profile() {
+        let without_profile = upscaled_etag(&header("\"abc\""), "1234", None).unwrap();
+        let with_profile = upscaled_etag(&header("\"abc\""), "1234", Some("restore")).unwrap();
+        assert!(!matches(Some(&without_profile), &with_profile));
+        assert!(!matches(Some(&with_profile), &without_profile));
+        assert!(matches(Some(&with_profile), &with_profile));
+    }
+
+    #[test]
+    fn matches_uses_weak_comparison() {
+        let etag = header("\"abc-k1234\"");
+        assert!(matches(Some(&header("W/\"abc-k1234\"")), &etag));
+        assert!(matches(Some(&header("\"x\", \"abc-k1234\"")), &etag));
+        assert!(matches(Some(&header("*")), &etag));
+        assert!(!matches(Some(&header("\"abc\"")), &etag));
+        assert!(!matches(None, &etag));
+    }
+}
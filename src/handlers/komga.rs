@@ -21,7 +21,7 @@ pub async fn check_tags_on_series_metadata_update(
 
     if let Some(_) = json.tags {
         state.upscale_tag_checker.invalidate_cache();
-        state.upscale_call_history_cache.invalidate_all();
+        state.upscaled_cache.invalidate_all();
     }
 
     Ok(response)
@@ -43,7 +43,7 @@ pub async fn check_tags_on_book_metadata_update(
 
     if let Some(_) = json.tags {
         state.upscale_tag_checker.invalidate_cache();
-        state.upscale_call_history_cache.invalidate_all();
+        state.upscaled_cache.invalidate_all();
     }
 
     Ok(response)
@@ -0,0 +1,7 @@
+use axum::response::IntoResponse;
+
+use crate::metrics;
+
+pub async fn get_metrics() -> impl IntoResponse {
+    ([("content-type", "text/plain; version=0.0.4")], metrics::render())
+}
@@ -1,4 +1,5 @@
 pub mod upscale;
 pub mod config;
 pub mod proxy;
-pub mod komga;
\ No newline at end of file
+pub mod komga;
+pub mod metrics;
\ No newline at end of file
@@ -4,70 +4,127 @@ use axum::http::{Request, StatusCode};
 use axum::response::{IntoResponse, Response};
 use futures::{sink::SinkExt, stream::StreamExt};
 use hyper::Body;
-use log::error;
+use hyper::header::SEC_WEBSOCKET_PROTOCOL;
+use log::{error, info};
 use tokio::net::TcpStream;
+use tokio::time::{Duration, Instant, interval_at, sleep_until, timeout};
 use tokio_tungstenite::{MaybeTlsStream, tungstenite, WebSocketStream};
 
 use crate::app_state::AppState;
+use crate::config::app_config::WebsocketConfig;
 
 pub async fn proxy_handler(
     State(state): State<AppState>,
+    ws: Option<WebSocketUpgrade>,
     req: Request<Body>,
-) -> impl IntoResponse {
+) -> Response {
+    if let Some(ws) = ws {
+        return match ws_proxy(state, ws, req).await {
+            Ok(resp) => resp,
+            Err(status) => status.into_response()
+        };
+    }
+
     match state.proxy_client.proxy_request(req).await {
         Ok(resp) => resp.into_response(),
         Err(_) => StatusCode::BAD_GATEWAY.into_response()
     }
 }
 
-pub async fn kavita_ws_proxy_handler(
-    State(state): State<AppState>,
+async fn ws_proxy(
+    state: AppState,
     ws: WebSocketUpgrade,
     request: Request<Body>,
 ) -> Result<Response, StatusCode> {
-    let (upstream_socket, _) = match state.websocket_proxy_client.spawn_client(request.uri().path_and_query().unwrap().as_str()).await {
-        Ok(ok) => { ok }
-        Err(err) => {
-            error!("{}", err.to_string());
+    let config = state.config.websocket.clone();
+    let path_and_query = request.uri().path_and_query().map(|path| path.as_str()).unwrap_or("/");
+    let connect = state.websocket_proxy_client.spawn_client(path_and_query, request.headers());
+    let (upstream_socket, upstream_response) = match timeout(Duration::from_secs(config.connect_timeout), connect).await {
+        Ok(Ok(ok)) => { ok }
+        Ok(Err(err)) => {
+            error!("{}", err);
             return Err(StatusCode::BAD_GATEWAY);
         }
+        Err(_) => {
+            error!("websocket connection to upstream timed out");
+            return Err(StatusCode::GATEWAY_TIMEOUT);
+        }
+    };
+
+    // respond with subprotocol selected by upstream
+    let protocol = upstream_response.headers().get(SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|protocol| protocol.to_str().ok())
+        .map(|protocol| protocol.to_string());
+    let ws = match protocol {
+        Some(protocol) => ws.protocols([protocol]),
+        None => ws
     };
 
-    Ok(ws.on_upgrade(move |socket| handle_socket(socket, upstream_socket)))
+    Ok(ws.on_upgrade(move |socket| handle_socket(socket, upstream_socket, config)))
 }
 
 async fn handle_socket(
     incoming_socket: WebSocket,
     upstream_socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    config: WebsocketConfig,
 ) {
     let (mut incoming_sender, mut incoming_receiver) = incoming_socket.split();
     let (mut upstream_sender, mut upstream_receiver) = upstream_socket.split();
 
-    let mut incoming_send_task = tokio::spawn(async move {
-        while let Some(Ok(msg)) = incoming_receiver.next().await {
-            upstream_sender.send(to_tungstenite(msg)).await.unwrap();
-        }
-    });
-
-    let mut upstream_send_task = tokio::spawn(async move {
-        while let Some(Ok(msg)) = upstream_receiver.next().await {
-            let axum_message = match from_tungstenite(msg) {
-                Some(msg) => msg,
-                None => continue
-            };
+    // zero disables ping or idle timeout
+    let ping_period = Duration::from_secs(config.ping_interval.max(1));
+    let mut ping = interval_at(Instant::now() + ping_period, ping_period);
+    let idle_timeout = Duration::from_secs(config.idle_timeout);
+    let mut last_activity = Instant::now();
 
-            incoming_sender.send(axum_message).await.unwrap();
-        }
-    });
-
-    tokio::select! {
-        _ = (&mut incoming_send_task) => {
-            upstream_send_task.abort();
-        },
-        _ = (&mut upstream_send_task) => {
-            incoming_send_task.abort();
+    loop {
+        tokio::select! {
+            msg = incoming_receiver.next() => {
+                let msg = match msg {
+                    Some(Ok(msg)) => msg,
+                    _ => break
+                };
+                last_activity = Instant::now();
+                if let Err(err) = upstream_sender.send(to_tungstenite(msg)).await {
+                    error!("{}", err);
+                    break;
+                }
+            }
+            msg = upstream_receiver.next() => {
+                let msg = match msg {
+                    Some(Ok(msg)) => msg,
+                    _ => break
+                };
+                last_activity = Instant::now();
+                let axum_message = match from_tungstenite(msg) {
+                    Some(msg) => msg,
+                    None => continue
+                };
+                if let Err(err) = incoming_sender.send(axum_message).await {
+                    error!("{}", err);
+                    break;
+                }
+            }
+            _ = ping.tick(), if config.ping_interval != 0 => {
+                if let Err(err) = incoming_sender.send(Message::Ping(Vec::new())).await {
+                    error!("{}", err);
+                    break;
+                }
+                if let Err(err) = upstream_sender.send(tungstenite::Message::Ping(Vec::new())).await {
+                    error!("{}", err);
+                    break;
+                }
+            }
+            _ = sleep_until(last_activity + idle_timeout), if config.idle_timeout != 0 => {
+                info!("closing idle websocket connection");
+                break;
+            }
         }
     }
+
+    // when either side is done the other one is closed too. errors from already closed sockets are ignored
+    let _ = upstream_sender.close().await;
+    let _ = incoming_sender.close().await;
 }
 
 fn from_tungstenite(message: tungstenite::Message) -> Option<Message> {
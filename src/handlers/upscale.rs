@@ -1,16 +1,15 @@
 use std::future::Future;
 use std::path::Path;
-use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use axum::extract::State;
 use axum::http::{HeaderMap, HeaderValue, Request, Response, StatusCode};
 use axum::TypedHeader;
 use bytes::Bytes;
-use headers::{Authorization, Cookie};
+use headers::{Authorization, Cookie, Expires, HeaderMapExt};
 use headers::authorization::Basic;
 use hyper::Body;
 use hyper::body::to_bytes;
-use image::ImageFormat;
 use log::info;
 use moka::future::Cache;
 use once_cell::sync::Lazy;
@@ -19,11 +18,44 @@ use regex::Regex;
 use unicase::Ascii;
 
 use crate::app_state::AppState;
+use crate::config::app_config::{AppConfig, CacheHeaders, Format};
+use crate::content_negotiation::{FormatPreference, output_type};
+use crate::{etag, range};
 use crate::http_compression;
 use crate::http_compression::{Algorithm, compress};
 use crate::models::errors::HttpError;
+use crate::upscaler::image_type::ImageType;
 use crate::upscaler::upscale_actor::{UpscaleSupervisorActor, UpscaleSupervisorMessage};
 
+pub enum UpscaleTarget {
+    Skip,
+    Upscale(Option<String>),
+}
+
+// marks responses with upscaled image
+#[derive(Clone)]
+struct Upscaled;
+
+#[derive(Clone)]
+pub struct CachedUpscale {
+    status: StatusCode,
+    headers: HeaderMap<HeaderValue>,
+    body: Bytes,
+}
+
+impl CachedUpscale {
+    pub fn weight(&self) -> u32 {
+        self.body.len().try_into().unwrap_or(u32::MAX)
+    }
+
+    fn to_response(&self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        response
+    }
+}
+
 pub async fn upscale_komga(
     State(state): State<AppState>,
     authorization: Option<TypedHeader<Authorization<Basic>>>,
@@ -40,7 +72,11 @@ pub async fn upscale_komga(
             .find(|path| path[0] == "books")
             .map(|path| path[1])
             .unwrap();
-        tag_checker.komga_contains_upscale_tag(book_id, cookie, auth).await
+        if !tag_checker.komga_contains_upscale_tag(book_id, cookie.clone(), auth.clone()).await? {
+            return Ok(UpscaleTarget::Skip);
+        }
+        let profile = tag_checker.komga_profile(book_id, cookie, auth).await?;
+        Ok(UpscaleTarget::Upscale(profile))
     };
 
     upscale(state, req, upscale_condition).await
@@ -50,7 +86,7 @@ pub async fn upscale_kavita(
     State(state): State<AppState>,
     req: Request<Body>,
 ) -> Result<Response<Body>, StatusCode> {
-    upscale(state, req, || async { Ok(true) }).await
+    upscale(state, req, || async { Ok(UpscaleTarget::Upscale(None)) }).await
 }
 
 pub async fn upscale<F, Fut>(
@@ -60,95 +96,215 @@ pub async fn upscale<F, Fut>(
 ) -> Result<Response<Body>, StatusCode>
     where
         F: FnOnce() -> Fut,
-        Fut: Future<Output=Result<bool, HttpError>>
+        Fut: Future<Output=Result<UpscaleTarget, HttpError>>
 {
-    let request = to_proxy_request(state.upscale_call_history_cache.clone(), request);
-    let request_path = request.uri().path_and_query()
-        .map(|path| path.to_string())
-        .unwrap_or("/".to_string());
-
     let uri_str = format!("{} {}", request.method().as_str(), request.uri().path());
 
-    let response = state.proxy_client.proxy_request(request).await
+    let format_preference = FormatPreference::from_headers(request.headers());
+    let settings_hash = etag::settings_hash(&state.config, &format_preference);
+    let if_none_match = request.headers().get("if-none-match").cloned();
+    let accept_encoding = request.headers().get("accept-encoding").cloned();
+    let range = request.headers().get("range").cloned();
+    let if_range = request.headers().get("if-range").cloned();
+    let upstream_request = to_proxy_request(&request, Some(&settings_hash));
+
+    let mut response = state.proxy_client.proxy_request(upstream_request).await
         .map_err(|_| StatusCode::BAD_GATEWAY)?;
     info!("{}: upstream response: {}",uri_str, response.status());
-
-    if response.status() == 304 || !response.status().is_success() {
+    if response.status() != 304 && !response.status().is_success() {
         return Ok(response);
     }
-    let should_upscale = upscale_condition().await.map_err(|_| StatusCode::BAD_GATEWAY)?;
-    if !should_upscale { return Ok(response); }
 
-    let upscaled = upscale_response(response, state.upscaler).await;
+    let upscaled_cache_headers = &state.config.cache_headers.upscaled;
+    let target = upscale_condition().await;
+    // upstream confirms only the original image. etag of the client must also match current upscale target
+    if response.status() == 304 {
+        let etag = match &target {
+            Ok(UpscaleTarget::Upscale(profile)) => response.headers().get("etag")
+                .and_then(|upstream_etag| etag::upscaled_etag(upstream_etag, &settings_hash, profile.as_deref()))
+                .filter(|etag| etag::matches(if_none_match.as_ref(), etag)),
+            _ => None
+        };
+        if let Some(etag) = etag {
+            return Ok(with_cache_headers(not_modified_response(response.headers(), Some(etag)), upscaled_cache_headers));
+        }
+
+        info!("{}: cached image doesn't match upscale target. requesting full image", uri_str);
+        response = state.proxy_client.proxy_request(to_proxy_request(&request, None)).await
+            .map_err(|_| StatusCode::BAD_GATEWAY)?;
+        if !response.status().is_success() {
+            return Ok(response);
+        }
+    }
+
+    let passthrough_cache_headers = &state.config.cache_headers.passthrough;
+    let profile = match target {
+        Ok(UpscaleTarget::Upscale(profile)) => profile,
+        Ok(UpscaleTarget::Skip) => return Ok(range::serve_range(with_cache_headers(response, passthrough_cache_headers), range, if_range).await),
+        Err(_) => {
+            info!("{}: can't check upscale condition. skipping upscale", uri_str);
+            return Ok(range::serve_range(with_cache_headers(response, passthrough_cache_headers), range, if_range).await);
+        }
+    };
+
+    let etag = response.headers().get("etag")
+        .and_then(|upstream_etag| etag::upscaled_etag(upstream_etag, &settings_hash, profile.as_deref()));
+    if let Some(etag) = etag.as_ref().filter(|etag| etag::matches(if_none_match.as_ref(), etag)) {
+        info!("{}: upscaled image is not modified", uri_str);
+        return Ok(with_cache_headers(not_modified_response(response.headers(), Some(etag.clone())), upscaled_cache_headers));
+    }
+
+    // cached images are keyed by request path and upscaled version. without upstream etag settings and profile are used.
+    // encoding of cached response depends on Accept-Encoding
+    let version = etag.as_ref()
+        .and_then(|etag| etag.to_str().ok())
+        .map(|etag| etag.to_string())
+        .unwrap_or_else(|| format!("k{} {}", settings_hash, profile.as_deref().unwrap_or_default()));
+    let path_and_query = request.uri().path_and_query().map(|path| path.as_str()).unwrap_or_else(|| request.uri().path());
+    let cache_key = match accept_encoding.as_ref().and_then(|value| value.to_str().ok()) {
+        Some(accept_encoding) if state.config.compress_uncompressed_images => format!("{} {} {}", path_and_query, version, accept_encoding),
+        _ => format!("{} {}", path_and_query, version)
+    };
+    if let Some(cached) = state.upscaled_cache.get(&cache_key) {
+        info!("{}: serving upscaled image from cache", uri_str);
+        return Ok(range::serve_range(with_cache_headers(cached.to_response(), upscaled_cache_headers), range, if_range).await);
+    }
+
+    let upscaled = upscale_response(response, state.upscaler, &state.config, &format_preference, accept_encoding, profile, etag).await;
     info!("{} finished upscaling", uri_str);
-    state.upscale_call_history_cache.insert(request_path, ()).await;
-    Ok(upscaled)
+
+    // passthrough responses are not cached
+    let upscaled = if upscaled.extensions().get::<Upscaled>().is_some() {
+        cache_upscaled(&state.upscaled_cache, cache_key, upscaled).await
+    } else {
+        upscaled
+    };
+    Ok(range::serve_range(upscaled, range, if_range).await)
+}
+
+async fn cache_upscaled(cache: &Cache<String, CachedUpscale>, key: String, response: Response<Body>) -> Response<Body> {
+    let (parts, body) = response.into_parts();
+    let body = match to_bytes(body).await {
+        Ok(body) => body,
+        Err(_) => return Response::from_parts(parts, Body::empty())
+    };
+
+    let cached = CachedUpscale { status: parts.status, headers: parts.headers.clone(), body: body.clone() };
+    cache.insert(key, cached).await;
+    Response::from_parts(parts, Body::from(body))
 }
 
 async fn upscale_response(
     response: Response<Body>,
     upscaler: ActorRef<UpscaleSupervisorActor>,
+    config: &AppConfig,
+    format_preference: &FormatPreference,
+    accept_encoding: Option<HeaderValue>,
+    profile: Option<String>,
+    etag: Option<HeaderValue>,
 ) -> Response<Body> {
     let status = response.status();
     let headers = response.headers().clone();
-    let mut content_type = headers.get("content-type").unwrap().to_str().unwrap();
-    if content_type == "image/jpg" {
-        content_type = "image/jpeg"
-    }
-    let image_format = ImageFormat::from_mime_type(content_type).unwrap();
+    let content_type = headers.get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .and_then(ImageType::from_mime_type);
 
-    let encoding = headers.get("content-encoding");
     let response_bytes = to_bytes(response).await.unwrap();
+    let encodings = match http_compression::content_encodings(&headers) {
+        Some(encodings) => encodings,
+        None => {
+            info!("unsupported content encoding. skipping upscale");
+            return with_cache_headers(passthrough_response(status, response_bytes, &headers), &config.cache_headers.passthrough);
+        }
+    };
 
-    let decompressed = encoding
-        .map(unwrap_encoding_header)
-        .map(|algo| http_compression::decompress(response_bytes.clone(), algo));
+    let to_upscale = match http_compression::decompress_all(response_bytes.clone(), &encodings).await {
+        Ok(decompressed) => decompressed,
+        Err(err) => {
+            info!("can't decompress response: {}. skipping upscale", err);
+            return with_cache_headers(passthrough_response(status, response_bytes, &headers), &config.cache_headers.passthrough);
+        }
+    };
 
-    let to_upscale = match decompressed {
-        None => response_bytes,
-        Some(decompressed) => decompressed.await.unwrap()
+    let image_type = match content_type.or_else(|| ImageType::from_magic_bytes(&to_upscale)) {
+        Some(image_type) => image_type,
+        None => {
+            info!("unknown image type. skipping upscale");
+            return with_cache_headers(passthrough_response(status, response_bytes, &headers), &config.cache_headers.passthrough);
+        }
     };
+    let return_type = output_type(config, format_preference, image_type);
 
-    let (upscaled, format) =
-        call!(upscaler, UpscaleSupervisorMessage::Upscale, to_upscale, image_format).unwrap();
+    let upscale_result =
+        call!(upscaler, UpscaleSupervisorMessage::Upscale, to_upscale, image_type, return_type, profile).unwrap();
+    let upscaled = match upscale_result {
+        Ok(upscaled) => upscaled,
+        Err(err) => {
+            info!("can't upscale image: {}. skipping upscale", err);
+            return with_cache_headers(passthrough_response(status, response_bytes, &headers), &config.cache_headers.passthrough);
+        }
+    };
+    // skipped or rejected images keep upstream etag so that next request retries upscale
+    if !upscaled.upscaled {
+        return with_cache_headers(passthrough_response(status, response_bytes, &headers), &config.cache_headers.passthrough);
+    }
 
-    let body_to_compress = upscaled.clone();
-    let compressed = encoding
-        .map(unwrap_encoding_header)
-        .map(|algo| compress(body_to_compress, algo));
+    let format = upscaled.image_type;
+    let spread = upscaled.spread;
+    let upscaled = upscaled.bytes;
 
-    let response_body = match compressed {
-        None => upscaled,
-        Some(compressed) => compressed.await.unwrap()
+    // images are sent with identity encoding. compression only helps formats that are not compressed already
+    let encoding = if config.compress_uncompressed_images && format.is_uncompressed() {
+        http_compression::negotiate(accept_encoding.as_ref())
+    } else {
+        None
+    };
+    let (response_body, encoding) = match encoding {
+        None => (upscaled, None),
+        Some(algorithm) => match compress(upscaled.clone(), algorithm).await {
+            Ok(compressed) => (compressed, Some(algorithm)),
+            Err(err) => {
+                info!("can't compress upscaled image: {}. sending uncompressed", err);
+                (upscaled, None)
+            }
+        }
     };
 
-    to_response(status, response_body, &headers, format)
+    let negotiated = matches!(config.return_format, Format::Negotiate);
+    let mut response = to_response(status, response_body, &headers, format, negotiated, etag, encoding);
+    if config.compress_uncompressed_images {
+        response.headers_mut().append("Vary", HeaderValue::from_static("Accept-Encoding"));
+    }
+    if spread {
+        response.headers_mut().insert("X-Kurp-Spread", HeaderValue::from_static("true"));
+    }
+    response.extensions_mut().insert(Upscaled);
+    with_cache_headers(response, &config.cache_headers.upscaled)
 }
 
 fn to_response(
     status: StatusCode,
     bytes: Bytes,
     headers: &HeaderMap<HeaderValue>,
-    format: ImageFormat,
+    format: ImageType,
+    negotiated: bool,
+    etag: Option<HeaderValue>,
+    encoding: Option<Algorithm>,
 ) -> Response<Body> {
-    let mime_type = match format {
-        ImageFormat::Png => { Some(("image/png", "png")) }
-        ImageFormat::Jpeg => { Some(("image/jpeg", "jpeg")) }
-        ImageFormat::WebP => { Some(("image/webp", "webp")) }
-        _ => { None }
-    };
     let mut builder = Response::builder();
     for (k, v) in headers {
-        if Ascii::new("Content-Length") == k {
+        if Ascii::new("ETag") == k || Ascii::new("Last-Modified") == k || Ascii::new("Content-Encoding") == k {
+            continue;
+        } else if Ascii::new("Content-Length") == k {
             builder = builder.header("Content-Length", bytes.len())
-        } else if Ascii::new("Content-Type") == k && mime_type.is_some() {
-            builder = builder.header("Content-Type", mime_type.unwrap().0)
-        } else if Ascii::new("Content-Disposition") == k && mime_type.is_some() {
+        } else if Ascii::new("Content-Type") == k {
+            builder = builder.header("Content-Type", format.mime_type())
+        } else if Ascii::new("Content-Disposition") == k {
             let new_value: String = v.to_str().unwrap().split("; ")
                 .map(|param| if param.starts_with("filename=") {
-                    with_new_file_extension(param, mime_type.unwrap().1)
+                    with_new_file_extension(param, format.extension())
                 } else if param.starts_with("filename*=") {
-                    with_new_file_extension(param, mime_type.unwrap().1)
+                    with_new_file_extension(param, format.extension())
                 } else { param.to_string() })
                 .collect::<Vec<String>>().join("; ");
             builder = builder.header("Content-Disposition", new_value)
@@ -156,12 +312,70 @@ fn to_response(
             builder = builder.header(k, v);
         }
     }
+    if negotiated {
+        builder = builder.header("Vary", "Accept, Save-Data")
+    }
+    if let Some(etag) = etag {
+        builder = builder.header("ETag", etag)
+    }
+    if let Some(encoding) = encoding {
+        builder = builder.header("Content-Encoding", encoding.coding())
+    }
+    builder
+        .status(status)
+        .body(Body::from(bytes))
+        .unwrap()
+}
+
+// 304 must not have a body. only caching related headers are kept
+fn not_modified_response(headers: &HeaderMap<HeaderValue>, etag: Option<HeaderValue>) -> Response<Body> {
+    static NOT_MODIFIED_HEADERS: Lazy<Vec<Ascii<&'static str>>> = Lazy::new(|| {
+        vec![Ascii::new("Cache-Control"), Ascii::new("Expires"), Ascii::new("Vary"), Ascii::new("Date")]
+    });
+
+    let mut builder = Response::builder();
+    for (k, v) in headers {
+        if NOT_MODIFIED_HEADERS.iter().any(|h| h == &k.as_str()) {
+            builder = builder.header(k, v);
+        }
+    }
+    if let Some(etag) = etag {
+        builder = builder.header("ETag", etag)
+    }
+    builder
+        .status(StatusCode::NOT_MODIFIED)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn passthrough_response(
+    status: StatusCode,
+    bytes: Bytes,
+    headers: &HeaderMap<HeaderValue>,
+) -> Response<Body> {
+    let mut builder = Response::builder();
+    for (k, v) in headers {
+        builder = builder.header(k, v);
+    }
     builder
         .status(status)
         .body(Body::from(bytes))
         .unwrap()
 }
 
+fn with_cache_headers(mut response: Response<Body>, cache_headers: &CacheHeaders) -> Response<Body> {
+    let headers = response.headers_mut();
+    if let Some(cache_control) = cache_headers.cache_control.as_ref()
+        .and_then(|cache_control| HeaderValue::from_str(cache_control).ok()) {
+        headers.insert("Cache-Control", cache_control);
+        headers.remove("Expires");
+    }
+    if let Some(expires) = cache_headers.expires {
+        headers.typed_insert(Expires::from(SystemTime::now() + Duration::from_secs(expires)));
+    }
+    response
+}
+
 fn with_new_file_extension(name: &str, extension: &str) -> String {
     let regex = Regex::new(r"(filename\*=UTF-8''|filename=)(.+\b)").unwrap();
     let captures = regex.captures(name).unwrap();
@@ -172,63 +386,27 @@ fn with_new_file_extension(name: &str, extension: &str) -> String {
     format!("{}{}", param_name, new_filename)
 }
 
-fn unwrap_encoding_header(encoding: &HeaderValue) -> Algorithm {
-    let encoding = encoding.to_str().unwrap();
-    match encoding {
-        "gzip" => Algorithm::Gzip,
-        "deflate" => Algorithm::Deflate,
-        "br" => Algorithm::Brotli,
-        _ => panic!("unsupported compression algorithm")
-    }
-}
-
-fn to_proxy_request(
-    call_cache: Arc<Cache<String, ()>>,
-    req: Request<Body>,
-) -> Request<Body> {
-    let request_path = req.uri().path_and_query()
-        .map(|path| path.to_string())
-        .unwrap_or("/".to_string());
-
-    let (parts, body) = req.into_parts();
-
-    let headers = remove_uncached_conditional_headers(
-        parts.headers,
-        call_cache.clone(),
-        request_path,
-    );
-
+// etags of upscaled images are translated back to upstream etags. If-None-Match is dropped without settings hash.
+// If-Modified-Since is dropped because upstream modification date doesn't reflect upscaler settings changes.
+// ranges are served from the upscaled image so full image is always requested
+fn to_proxy_request(req: &Request<Body>, settings_hash: Option<&str>) -> Request<Body> {
     let mut builder = Request::builder()
-        .method(parts.method)
-        .uri(parts.uri)
-        .version(parts.version)
-        .extension(parts.extensions);
-    builder.headers_mut().unwrap().extend(headers);
-
-    builder.body(body).unwrap()
-}
-
-fn is_conditional_header(header_name: &str) -> bool {
-    static CONDITIONAL_HEADERS: Lazy<Vec<Ascii<&'static str>>> = Lazy::new(|| {
-        vec![Ascii::new("If-Modified-Since"), Ascii::new("If-None-Match")]
-    });
-    CONDITIONAL_HEADERS.iter().any(|h| h == &header_name)
-}
+        .method(req.method().clone())
+        .uri(req.uri().clone())
+        .version(req.version());
+    let headers = builder.headers_mut().unwrap();
+    for (k, v) in req.headers().iter() {
+        if Ascii::new("If-Modified-Since") == k || Ascii::new("Range") == k || Ascii::new("If-Range") == k {
+            continue;
+        } else if Ascii::new("If-None-Match") == k {
+            if let Some(upstream_etags) = settings_hash.and_then(|settings_hash| etag::to_upstream_if_none_match(v, settings_hash)) {
+                headers.append(k, upstream_etags);
+            }
+        } else {
+            headers.append(k, v.clone());
+        }
+    }
 
-fn remove_uncached_conditional_headers(
-    headers: HeaderMap<HeaderValue>,
-    call_cache: Arc<Cache<String, ()>>,
-    request_path: String,
-) -> HeaderMap<HeaderValue> {
-    if call_cache.get(&request_path).is_some() {
-        return headers;
-    }
-
-    headers.iter()
-        .filter_map(|(k, v)|
-            if is_conditional_header(k.as_str()) {
-                None
-            } else {
-                Some((k.clone(), v.clone()))
-            }).collect()
+    // image requests have no body
+    builder.body(Body::empty()).unwrap()
 }
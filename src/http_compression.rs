@@ -2,7 +2,8 @@ use std::io::Result;
 use std::ops::Deref;
 
 use async_compression::Level;
-use async_compression::tokio::write::{BrotliDecoder, BrotliEncoder, DeflateDecoder, DeflateEncoder, GzipDecoder, GzipEncoder};
+use async_compression::tokio::write::{BrotliDecoder, BrotliEncoder, DeflateDecoder, DeflateEncoder, GzipDecoder, GzipEncoder, ZstdDecoder, ZstdEncoder};
+use axum::http::{HeaderMap, HeaderValue};
 use bytes::Bytes;
 use Level::Fastest;
 use tokio::io::AsyncWriteExt;
@@ -27,6 +28,12 @@ pub async fn decompress(bytes: Bytes, algorithm: Algorithm) -> Result<Bytes> {
             decoder.shutdown().await?;
             Ok(Bytes::from(decoder.into_inner()))
         }
+        Algorithm::Zstd => {
+            let mut decoder = ZstdDecoder::new(Vec::new());
+            decoder.write_all(bytes.deref()).await?;
+            decoder.shutdown().await?;
+            Ok(Bytes::from(decoder.into_inner()))
+        }
     }
 }
 
@@ -50,11 +57,143 @@ pub async fn compress(bytes: Bytes, algorithm: Algorithm) -> Result<Bytes> {
             encoder.shutdown().await?;
             Ok(Bytes::from(encoder.into_inner()))
         }
+        Algorithm::Zstd => {
+            let mut encoder = ZstdEncoder::with_quality(Vec::new(), Fastest);
+            encoder.write_all(bytes.deref()).await?;
+            encoder.shutdown().await?;
+            Ok(Bytes::from(encoder.into_inner()))
+        }
+    }
+}
+
+// codings are listed in order they were applied, decoding goes in reverse
+pub async fn decompress_all(bytes: Bytes, algorithms: &[Algorithm]) -> Result<Bytes> {
+    let mut bytes = bytes;
+    for algorithm in algorithms.iter().rev() {
+        bytes = decompress(bytes, *algorithm).await?;
     }
+    Ok(bytes)
+}
+
+// server preference order is used between codings with equal q value
+pub fn negotiate(accept_encoding: Option<&HeaderValue>) -> Option<Algorithm> {
+    let accepted: Vec<(String, f32)> = accept_encoding
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|coding| {
+            let mut params = coding.split(';');
+            let coding = params.next()?.trim().to_ascii_lowercase();
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((coding, q))
+        })
+        .collect();
+
+    [Algorithm::Zstd, Algorithm::Brotli, Algorithm::Gzip, Algorithm::Deflate].into_iter()
+        .filter_map(|algorithm| accepted.iter()
+            .find(|(coding, _)| coding == algorithm.coding() || coding == "*")
+            .map(|(_, q)| (algorithm, *q))
+        )
+        .filter(|(_, q)| *q > 0.0)
+        .fold(None, |best: Option<(Algorithm, f32)>, candidate| match best {
+            Some(best) if best.1 >= candidate.1 => Some(best),
+            _ => Some(candidate)
+        })
+        .map(|(algorithm, _)| algorithm)
+}
+
+// None if any of the codings is not supported
+pub fn content_encodings(headers: &HeaderMap<HeaderValue>) -> Option<Vec<Algorithm>> {
+    headers.get_all("content-encoding").iter()
+        .map(|value| value.to_str().ok())
+        .collect::<Option<Vec<&str>>>()?
+        .into_iter()
+        .flat_map(|value| value.split(','))
+        .map(|coding| coding.trim().to_ascii_lowercase())
+        .filter(|coding| !coding.is_empty() && coding != "identity")
+        .map(|coding| Algorithm::from_coding(&coding))
+        .collect()
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Algorithm {
     Brotli,
     Gzip,
     Deflate,
-}
\ No newline at end of file
+    Zstd,
+}
+
+impl Algorithm {
+    pub fn from_coding(coding: &str) -> Option<Self> {
+        match coding {
+            "br" => Some(Algorithm::Brotli),
+            "gzip" | "x-gzip" => Some(Algorithm::Gzip),
+            "deflate" => Some(Algorithm::Deflate),
+            "zstd" => Some(Algorithm::Zstd),
+            _ => None
+        }
+    }
+
+    pub fn coding(&self) -> &'static str {
+        match self {
+            Algorithm::Brotli => "br",
+            Algorithm::Gzip => "gzip",
+            Algorithm::Deflate => "deflate",
+            Algorithm::Zstd => "zstd",
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encodings(values: &[&'static str]) -> Option<Vec<Algorithm>> {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append("content-encoding", HeaderValue::from_static(value));
+        }
+        content_encodings(&headers)
+    }
+
+    fn negotiate_str(accept_encoding: &'static str) -> Option<Algorithm> {
+        negotiate(Some(&HeaderValue::from_static(accept_encoding)))
+    }
+
+    #[test]
+    fn parses_content_encoding_chain() {
+        assert_eq!(encodings(&[]), Some(vec![]));
+        assert_eq!(encodings(&["identity"]), Some(vec![]));
+        assert_eq!(encodings(&["gzip, br"]), Some(vec![Algorithm::Gzip, Algorithm::Brotli]));
+        assert_eq!(encodings(&["X-GZIP", "zstd"]), Some(vec![Algorithm::Gzip, Algorithm::Zstd]));
+    }
+
+    #[test]
+    fn unknown_content_encoding_is_unsupported() {
+        assert_eq!(encodings(&["compress"]), None);
+        assert_eq!(encodings(&["gzip, compress"]), None);
+    }
+
+    #[test]
+    fn negotiates_highest_q_value() {
+        assert_eq!(negotiate(None), None);
+        assert_eq!(negotiate_str("identity"), None);
+        assert_eq!(negotiate_str("gzip;q=1, br;q=0.5"), Some(Algorithm::Gzip));
+        assert_eq!(negotiate_str("deflate, gzip;q=0.8"), Some(Algorithm::Deflate));
+    }
+
+    #[test]
+    fn negotiates_server_preference_on_equal_q_value() {
+        assert_eq!(negotiate_str("gzip, br"), Some(Algorithm::Brotli));
+        assert_eq!(negotiate_str("*"), Some(Algorithm::Zstd));
+    }
+
+    #[test]
+    fn excludes_codings_with_zero_q_value() {
+        assert_eq!(negotiate_str("*;q=0"), None);
+        assert_eq!(negotiate_str("gzip;q=0"), None);
+        assert_eq!(negotiate_str("zstd;q=0, br;q=0, *"), Some(Algorithm::Gzip));
+    }
+}
@@ -0,0 +1,249 @@
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use axum::Router;
+use axum_server::accept::Accept;
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use futures::future::BoxFuture;
+use futures::{FutureExt, Stream, StreamExt};
+use log::{error, info, warn};
+use once_cell::sync::OnceCell;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::config::app_config::AppConfig;
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Protocol {
+    Http,
+    Https,
+}
+
+// systemd passes sockets only once. they are kept open and cloned for every server restart
+static SYSTEMD_LISTENERS: OnceCell<Vec<(Listener, Protocol)>> = OnceCell::new();
+
+const TLS_HANDSHAKE_CONCURRENCY: usize = 64;
+
+impl Listener {
+    fn try_clone(&self) -> io::Result<Listener> {
+        match self {
+            Listener::Tcp(listener) => listener.try_clone().map(Listener::Tcp),
+            Listener::Unix(listener) => listener.try_clone().map(Listener::Unix),
+        }
+    }
+}
+
+// sockets from systemd socket activation replace configured bind addresses.
+// sockets named "https" with FileDescriptorName= serve https, every other socket serves plain http
+pub fn bind(config: &AppConfig) -> io::Result<Vec<(Listener, Protocol)>> {
+    let systemd_listeners = SYSTEMD_LISTENERS.get_or_init(systemd_listeners);
+    if !systemd_listeners.is_empty() {
+        info!("using {} sockets from systemd socket activation", systemd_listeners.len());
+        return systemd_listeners.iter()
+            .map(|(listener, protocol)| listener.try_clone().map(|listener| (listener, *protocol)))
+            .collect();
+    }
+
+    let mut addresses = Vec::new();
+    if !config.tls.enabled || config.tls.keep_http {
+        let http_addresses = if config.bind.is_empty() {
+            vec![format!("0.0.0.0:{}", config.port)]
+        } else {
+            config.bind.clone()
+        };
+        addresses.extend(http_addresses.into_iter().map(|address| (address, Protocol::Http)));
+    }
+    if config.tls.enabled {
+        let https_addresses = if config.tls.bind.is_empty() {
+            vec![format!("0.0.0.0:{}", config.tls.port)]
+        } else {
+            config.tls.bind.clone()
+        };
+        addresses.extend(https_addresses.into_iter().map(|address| (address, Protocol::Https)));
+    }
+
+    addresses.iter()
+        .map(|(address, protocol)| bind_address(address, config.unix_socket_mode.as_str())
+            .map(|listener| (listener, *protocol)))
+        .collect()
+}
+
+fn bind_address(address: &str, unix_socket_mode: &str) -> io::Result<Listener> {
+    if let Some(path) = address.strip_prefix("unix:") {
+        // socket file left from previous run prevents bind. other files and sockets in use are never removed
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists and is not a unix socket", path)));
+            }
+            match UnixStream::connect(path) {
+                Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path)?,
+                _ => return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is used by another process", path))),
+            }
+        }
+        let listener = UnixListener::bind(path)?;
+        let mode = u32::from_str_radix(unix_socket_mode, 8)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid unix socket mode {}", unix_socket_mode)))?;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        info!("listening on unix socket {}", path);
+        return Ok(Listener::Unix(listener));
+    }
+
+    let addr: SocketAddr = address.parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid bind address {}", address)))?;
+    let listener = TcpListener::bind(addr)?;
+    info!("listening on {}", addr);
+    Ok(Listener::Tcp(listener))
+}
+
+fn systemd_listeners() -> Vec<(Listener, Protocol)> {
+    let fds = match sd_notify::listen_fds_with_names(true) {
+        Ok(fds) => fds,
+        Err(err) => {
+            warn!("can't read systemd sockets: {}", err);
+            return Vec::new();
+        }
+    };
+
+    fds.map(|(fd, name)| {
+        // getsockname of unix socket can't be converted to SocketAddr
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        let listener = match listener.local_addr() {
+            Ok(_) => Listener::Tcp(listener),
+            Err(_) => Listener::Unix(unsafe { UnixListener::from_raw_fd(listener.into_raw_fd()) })
+        };
+        let protocol = if name == "https" { Protocol::Https } else { Protocol::Http };
+        (listener, protocol)
+    }).collect()
+}
+
+pub fn notify_ready() {
+    if let Err(err) = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]) {
+        warn!("can't notify systemd: {}", err);
+    }
+}
+
+// https listeners are served only when tls config is loaded.
+// listener setup errors are returned before the server starts
+pub fn serve(listener: Listener, routes: Router, tls: Option<RustlsConfig>, shutdown: impl Future<Output=()> + Send + 'static)
+             -> io::Result<BoxFuture<'static, ()>> {
+    let server = match (listener, tls) {
+        (Listener::Tcp(listener), None) => {
+            listener.set_nonblocking(true)?;
+            axum::Server::from_tcp(listener).map_err(io::Error::other)?
+                .serve(routes.into_make_service())
+                .with_graceful_shutdown(shutdown)
+                .boxed()
+        }
+        (Listener::Tcp(listener), Some(tls)) => serve_incoming(with_tls(tcp_incoming(listener)?, tls), routes, shutdown).boxed(),
+        (Listener::Unix(listener), None) => serve_incoming(unix_incoming(listener)?, routes, shutdown).boxed(),
+        (Listener::Unix(listener), Some(tls)) => serve_incoming(with_tls(unix_incoming(listener)?, tls), routes, shutdown).boxed(),
+    };
+
+    Ok(server.map(|result| {
+        if let Err(err) = result {
+            error!("server error: {}", err);
+        }
+    }).boxed())
+}
+
+async fn serve_incoming<S, IO>(incoming: S, routes: Router, shutdown: impl Future<Output=()>) -> hyper::Result<()>
+    where
+        S: Stream<Item=IO> + Send,
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+    let incoming = incoming.map(Ok::<IO, io::Error>);
+    axum::Server::builder(hyper::server::accept::from_stream(incoming))
+        .serve(routes.into_make_service())
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+// accept errors are logged and skipped. hyper stops the server on the first error from custom incoming stream
+fn tcp_incoming(listener: TcpListener) -> io::Result<impl Stream<Item=tokio::net::TcpStream>> {
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    Ok(futures::stream::unfold(listener, |listener| async {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let _ = stream.set_nodelay(true);
+                    return Some((stream, listener));
+                }
+                Err(err) => error!("can't accept connection: {}", err)
+            }
+        }
+    }))
+}
+
+fn unix_incoming(listener: UnixListener) -> io::Result<impl Stream<Item=tokio::net::UnixStream>> {
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::UnixListener::from_std(listener)?;
+    Ok(futures::stream::unfold(listener, |listener| async {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => return Some((stream, listener)),
+                Err(err) => error!("can't accept connection: {}", err)
+            }
+        }
+    }))
+}
+
+// handshakes run concurrently so that slow clients don't block accepting new connections
+fn with_tls<S, IO>(incoming: S, tls: RustlsConfig) -> impl Stream<Item=impl AsyncRead + AsyncWrite + Unpin + Send + 'static>
+    where
+        S: Stream<Item=IO>,
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+    let acceptor = RustlsAcceptor::new(tls);
+    incoming
+        .map(move |stream| acceptor.accept(stream, ()))
+        .buffer_unordered(TLS_HANDSHAKE_CONCURRENCY)
+        .filter_map(|handshake| async move {
+            match handshake {
+                Ok((stream, _)) => Some(stream),
+                Err(err) => {
+                    info!("tls handshake failed: {}", err);
+                    None
+                }
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_only_stale_unix_socket() {
+        let path = std::env::temp_dir().join(format!("kurp-test-{}.sock", std::process::id()));
+        let address = format!("unix:{}", path.display());
+
+        let listener = bind_address(&address, "660").unwrap();
+        let err = bind_address(&address, "660").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+
+        drop(listener);
+        assert!(bind_address(&address, "660").is_ok());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn keeps_regular_file() {
+        let path = std::env::temp_dir().join(format!("kurp-test-{}.file", std::process::id()));
+        fs::write(&path, b"").unwrap();
+
+        let err = bind_address(&format!("unix:{}", path.display()), "660").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,5 +1,6 @@
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use hyper::Uri;
 use log::LevelFilter;
@@ -12,8 +13,10 @@ use crate::app_state::AppState;
 use crate::clients::kavita_client::KavitaClient;
 use crate::clients::komga_client::KomgaClient;
 use crate::clients::proxy_client::ProxyClient;
+use crate::clients::upstream_tls::UpstreamTls;
 use crate::clients::websocket_proxy_client::WebsocketProxyClient;
 use crate::config::app_config::AppConfig;
+use crate::handlers::upscale::CachedUpscale;
 use crate::tags_provider::UpscaleTagChecker;
 use crate::upscaler::upscale_actor::{UpscaleSupervisorActor, UpscaleSupervisorMessage};
 
@@ -26,6 +29,12 @@ mod clients;
 mod tags_provider;
 mod app_state;
 mod server;
+mod content_negotiation;
+mod etag;
+mod range;
+mod metrics;
+mod tls;
+mod listener;
 
 
 #[tokio::main]
@@ -46,10 +55,12 @@ async fn main() {
         upscale_actor.send_message(UpscaleSupervisorMessage::Init(config.clone()))
             .expect("Failed to send Upscaler Init message");
 
-        let (tx, graceful_shutdown_rx) = broadcast::channel::<()>(10);
+        let (tx, _) = broadcast::channel::<()>(10);
 
-        let reqwest_client = reqwest::Client::builder()
-            .redirect(Policy::none())
+        let upstream_tls = UpstreamTls::load(&config.upstream_tls)
+            .expect("Failed to load upstream tls config");
+        let reqwest_client = upstream_tls.apply(reqwest::Client::builder().redirect(Policy::none()))
+            .expect("Failed to apply upstream tls config")
             .build()
             .expect("Reqwest client couldn't build");
 
@@ -61,29 +72,38 @@ async fn main() {
 
         let tag_provider = Arc::new(UpscaleTagChecker::new(
             config.upscale_tag.clone(),
+            config.profiles.clone(),
             komga_client,
             kavita_client,
         ));
 
-        let upscale_call_cache = Cache::new(1_000);
         let proxy_client = ProxyClient::new(reqwest_client, upstream_url_str);
+        let ws_scheme = if upstream_url.scheme_str() == Some("https") { "wss" } else { "ws" };
         let ws_url = Uri::builder()
-            .scheme("ws")
+            .scheme(ws_scheme)
             .authority(upstream_url.authority().unwrap().as_str())
             .path_and_query(upstream_url.path())
             .build().unwrap();
         let ws_url_str = ws_url.to_string().strip_suffix("/").unwrap().to_string();
-        let websocket_proxy_client = WebsocketProxyClient::new(ws_url_str);
+        let websocket_connector = upstream_tls.websocket_connector()
+            .expect("Failed to apply upstream tls config");
+        let websocket_proxy_client = WebsocketProxyClient::new(ws_url_str, websocket_connector);
+
+        let upscaled_cache = Cache::builder()
+            .max_capacity(config.upscaled_cache_mb * 1024 * 1024)
+            .weigher(|_, cached: &CachedUpscale| cached.weight())
+            .time_to_live(Duration::from_secs(10 * 60))
+            .build();
 
         let state = AppState {
             config,
             upscaler: upscale_actor.clone(),
             proxy_client: Arc::new(proxy_client),
             websocket_proxy_client: Arc::new(websocket_proxy_client),
-            upscale_call_history_cache: Arc::new(upscale_call_cache),
+            upscaled_cache: Arc::new(upscaled_cache),
             upscale_tag_checker: tag_provider,
             shutdown_tx: tx,
         };
-        server::start(state, graceful_shutdown_rx).await;
+        server::start(state).await;
     }
 }
\ No newline at end of file
@@ -0,0 +1,13 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub static QUALITY_GUARD_REJECTIONS: AtomicU64 = AtomicU64::new(0);
+
+// prometheus text exposition format
+pub fn render() -> String {
+    format!(
+        "# HELP kurp_quality_guard_rejections_total Upscaled images rejected by quality guard\n\
+         # TYPE kurp_quality_guard_rejections_total counter\n\
+         kurp_quality_guard_rejections_total {}\n",
+        QUALITY_GUARD_REJECTIONS.load(Ordering::Relaxed)
+    )
+}
@@ -0,0 +1,144 @@
+use axum::http::{HeaderValue, Response, StatusCode};
+use hyper::Body;
+use hyper::body::to_bytes;
+
+// single byte range of the final response body. multiple ranges are answered with full body
+pub async fn serve_range(
+    response: Response<Body>,
+    range: Option<HeaderValue>,
+    if_range: Option<HeaderValue>,
+) -> Response<Body> {
+    let (mut parts, body) = response.into_parts();
+    parts.headers.insert("Accept-Ranges", HeaderValue::from_static("bytes"));
+    let range = match range.as_ref().and_then(|range| range.to_str().ok()) {
+        Some(range) if parts.status == StatusCode::OK => range.to_string(),
+        _ => return Response::from_parts(parts, body)
+    };
+    if let Some(if_range) = if_range {
+        if !parts.headers.get("etag").is_some_and(|etag| is_strong_match(&if_range, etag)) {
+            return Response::from_parts(parts, body);
+        }
+    }
+
+    let bytes = match to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            parts.status = StatusCode::INTERNAL_SERVER_ERROR;
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    let total = bytes.len() as u64;
+
+    match parse_range(&range, total) {
+        RangeResult::Full => Response::from_parts(parts, Body::from(bytes)),
+        RangeResult::Unsatisfiable => {
+            parts.status = StatusCode::RANGE_NOT_SATISFIABLE;
+            parts.headers.insert("Content-Range", HeaderValue::from_str(&format!("bytes */{}", total)).unwrap());
+            parts.headers.insert("Content-Length", HeaderValue::from(0));
+            Response::from_parts(parts, Body::empty())
+        }
+        RangeResult::Partial(start, end) => {
+            parts.status = StatusCode::PARTIAL_CONTENT;
+            parts.headers.insert("Content-Range", HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, total)).unwrap());
+            parts.headers.insert("Content-Length", HeaderValue::from(end - start + 1));
+            Response::from_parts(parts, Body::from(bytes.slice(start as usize..=end as usize)))
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum RangeResult {
+    Full,
+    Unsatisfiable,
+    Partial(u64, u64),
+}
+
+fn parse_range(range: &str, total: u64) -> RangeResult {
+    let spec = match range.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return RangeResult::Full
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return RangeResult::Full
+    };
+
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => return RangeResult::Unsatisfiable,
+            Ok(suffix) => (total.saturating_sub(suffix), total.saturating_sub(1)),
+            Err(_) => return RangeResult::Full
+        },
+        (start, "") => match start.parse::<u64>() {
+            Ok(start) => (start, total.saturating_sub(1)),
+            Err(_) => return RangeResult::Full
+        },
+        (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => (start, end.min(total.saturating_sub(1))),
+            _ => return RangeResult::Full
+        }
+    };
+
+    if total == 0 || start >= total {
+        RangeResult::Unsatisfiable
+    } else {
+        RangeResult::Partial(start, end)
+    }
+}
+
+// If-Range requires strong comparison. weak etags never match
+fn is_strong_match(if_range: &HeaderValue, etag: &HeaderValue) -> bool {
+    let etag = etag.as_bytes();
+    !etag.starts_with(b"W/") && if_range.as_bytes() == etag
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bounded_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), RangeResult::Partial(0, 99));
+        assert_eq!(parse_range(" bytes= 10 - 19 ", 1000), RangeResult::Partial(10, 19));
+        assert_eq!(parse_range("bytes=900-2000", 1000), RangeResult::Partial(900, 999));
+    }
+
+    #[test]
+    fn parses_open_range() {
+        assert_eq!(parse_range("bytes=500-", 1000), RangeResult::Partial(500, 999));
+        assert_eq!(parse_range("bytes=1000-", 1000), RangeResult::Unsatisfiable);
+        assert_eq!(parse_range("bytes=2000-3000", 1000), RangeResult::Unsatisfiable);
+    }
+
+    #[test]
+    fn parses_suffix_range() {
+        assert_eq!(parse_range("bytes=-100", 1000), RangeResult::Partial(900, 999));
+        assert_eq!(parse_range("bytes=-2000", 1000), RangeResult::Partial(0, 999));
+        assert_eq!(parse_range("bytes=-0", 1000), RangeResult::Unsatisfiable);
+    }
+
+    #[test]
+    fn empty_body_is_unsatisfiable() {
+        assert_eq!(parse_range("bytes=0-", 0), RangeResult::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-10", 0), RangeResult::Unsatisfiable);
+    }
+
+    #[test]
+    fn invalid_and_multiple_ranges_return_full_body() {
+        assert_eq!(parse_range("bytes=5-1", 1000), RangeResult::Full);
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), RangeResult::Full);
+        assert_eq!(parse_range("items=0-1", 1000), RangeResult::Full);
+        assert_eq!(parse_range("bytes=a-b", 1000), RangeResult::Full);
+        assert_eq!(parse_range("bytes=10", 1000), RangeResult::Full);
+    }
+
+    #[test]
+    fn if_range_uses_strong_comparison() {
+        let etag = HeaderValue::from_static("\"abc\"");
+        assert!(is_strong_match(&HeaderValue::from_static("\"abc\""), &etag));
+        assert!(!is_strong_match(&HeaderValue::from_static("\"abd\""), &etag));
+
+        let weak = HeaderValue::from_static("W/\"abc\"");
+        assert!(!is_strong_match(&weak, &weak));
+    }
+}
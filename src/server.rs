@@ -1,37 +1,72 @@
-use std::net::SocketAddr;
 use std::time::Duration;
 
 use axum::Router;
 use axum::routing::{any, get, patch, post};
+use futures::future::join_all;
 use futures::FutureExt;
-use log::info;
-use tokio::sync::broadcast::Receiver;
+use log::{error, info, warn};
 use tokio::time::sleep;
 
 use crate::app_state::AppState;
 use crate::handlers::config::{get_config, update_config};
+use crate::handlers::metrics::get_metrics;
 use crate::handlers::komga::{check_tags_on_book_metadata_update, check_tags_on_series_metadata_update};
-use crate::handlers::proxy::{kavita_ws_proxy_handler, proxy_handler};
+use crate::handlers::proxy::proxy_handler;
 use crate::handlers::upscale::{upscale_kavita, upscale_komga};
+use crate::{listener, tls};
+use crate::listener::Protocol;
 
-pub async fn start(state: AppState, mut shutdown_rx: Receiver<()>) {
+pub async fn start(state: AppState) {
     let config = state.config.clone();
-    let mut force_shutdown_rx = state.shutdown_tx.subscribe();
+    let shutdown_tx = state.shutdown_tx.clone();
+    let mut force_shutdown_rx = shutdown_tx.subscribe();
 
     let routes = make_routes(state);
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
-    let server = axum::Server::bind(&addr)
-        .serve(routes.into_make_service())
-        .with_graceful_shutdown(async move { shutdown_rx.recv().await.unwrap() });
+    let listeners = listener::bind(&config).expect("Failed to bind listen address");
+    let rustls_config = if config.tls.enabled && listeners.iter().any(|(_, protocol)| *protocol == Protocol::Https) {
+        tls::load(&config.tls).await
+    } else {
+        None
+    };
+    let certificate_watcher = rustls_config.clone()
+        .map(|rustls_config| tokio::spawn(tls::watch_certificate(rustls_config, config.tls.clone())));
+
+    let servers: Vec<_> = listeners.into_iter().filter_map(|(listener, protocol)| {
+        let tls = match protocol {
+            Protocol::Http => None,
+            Protocol::Https if rustls_config.is_some() => rustls_config.clone(),
+            Protocol::Https => {
+                warn!("tls is disabled or certificate can't be loaded. skipping https listener");
+                return None;
+            }
+        };
+        let mut shutdown_rx = shutdown_tx.subscribe();
+        match listener::serve(listener, routes.clone(), tls, async move { shutdown_rx.recv().await.unwrap() }) {
+            Ok(server) => Some(server),
+            Err(err) => {
+                error!("can't start listener: {}", err);
+                None
+            }
+        }
+    }).collect();
+    // returning here would restart the server and reinitialize upscalers in a loop
+    if servers.is_empty() {
+        panic!("No listener can be served");
+    }
+    let servers = join_all(servers);
+    listener::notify_ready();
 
     let force_shutdown = force_shutdown_rx.recv()
         .then(|_| async { sleep(Duration::from_secs(1)).await; });
 
     tokio::select! {
-        _ = server => { info!("Graceful server shutdown") }
+        _ = servers => { info!("Graceful server shutdown") }
         _ = force_shutdown  => { info!("Forced server shutdown") }
     }
+    if let Some(certificate_watcher) = certificate_watcher {
+        certificate_watcher.abort();
+    }
 }
 
 fn make_routes(state: AppState) -> Router {
@@ -40,11 +75,11 @@ fn make_routes(state: AppState) -> Router {
     let mut routes = Router::new()
         .route("/api/v1/books/:book_id/pages/:page_number", get(upscale_komga))
         .route("/api/reader/image", get(upscale_kavita))
-        .route("/hubs/messages", get(kavita_ws_proxy_handler))
+        .route("/kurp/metrics", get(get_metrics))
         .route("/", any(proxy_handler))
         .route("/*any", any(proxy_handler));
 
-    if let Some(_) = &config.upscale_tag {
+    if config.upscale_tag.is_some() || !config.profiles.is_empty() {
         routes = routes
             .route("/api/v1/series/:series_id/metadata", patch(check_tags_on_series_metadata_update))
             .route("/api/v1/books/:book_id/metadata", patch(check_tags_on_book_metadata_update))
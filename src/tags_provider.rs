@@ -8,22 +8,33 @@ use unicase::Ascii;
 
 use crate::clients::kavita_client::KavitaClient;
 use crate::clients::komga_client::KomgaClient;
+use crate::config::app_config::UpscaleProfile;
 use crate::models::errors::HttpError;
 
 pub struct UpscaleTagChecker {
     upscale_tag: Option<String>,
+    profiles: Vec<UpscaleProfile>,
     komga: Arc<KomgaClient>,
     kavita: Arc<KavitaClient>,
     cache: Cache<String, bool>,
+    profile_cache: Cache<String, Option<String>>,
 }
 
 impl UpscaleTagChecker {
-    pub fn new(upscale_tag: Option<String>, komga: Arc<KomgaClient>, kavita: Arc<KavitaClient>) -> Self {
+    pub fn new(
+        upscale_tag: Option<String>,
+        profiles: Vec<UpscaleProfile>,
+        komga: Arc<KomgaClient>,
+        kavita: Arc<KavitaClient>,
+    ) -> Self {
         let cache = Cache::builder()
             .time_to_live(Duration::from_secs(3 * 60))
             .build();
+        let profile_cache = Cache::builder()
+            .time_to_live(Duration::from_secs(3 * 60))
+            .build();
 
-        Self { upscale_tag, komga, kavita, cache }
+        Self { upscale_tag, profiles, komga, kavita, cache, profile_cache }
     }
 
     pub async fn kavita_contains_upscale_tag(
@@ -59,6 +70,26 @@ impl UpscaleTagChecker {
         }
     }
 
+    // name of the first profile with tag present in book or series tags
+    pub async fn komga_profile(
+        &self,
+        book_id: &str,
+        cookie: Option<Cookie>,
+        auth: Option<Authorization<Basic>>,
+    ) -> Result<Option<String>, HttpError> {
+        if self.profiles.is_empty() { return Ok(None); }
+        if let Some(profile) = self.profile_cache.get(book_id) { return Ok(profile); }
+
+        let tags = self.get_komga_tags(book_id, cookie, auth).await?;
+        let profile = self.profiles.iter()
+            .find(|profile| tags.iter().any(|tag| Ascii::new(tag) == Ascii::new(&profile.tag)))
+            .map(|profile| profile.name.clone());
+
+        self.profile_cache.insert(book_id.to_string(), profile.clone()).await;
+
+        Ok(profile)
+    }
+
     async fn check_komga_tags(
         &self,
         upscale_tag: &String,
@@ -67,9 +98,7 @@ impl UpscaleTagChecker {
         auth: Option<Authorization<Basic>>,
     ) -> Result<bool, HttpError> {
         let upscale_tag = Ascii::new(upscale_tag);
-        let book = self.komga.get_book(book_id, cookie.clone(), auth.clone()).await?;
-        let series = self.komga.get_series(&book.series_id, cookie, auth).await?;
-        let contains_tag = book.metadata.tags.iter().chain(series.metadata.tags.iter())
+        let contains_tag = self.get_komga_tags(book_id, cookie, auth).await?.iter()
             .map(|el| Ascii::new(el))
             .any(|el| el == upscale_tag);
 
@@ -78,6 +107,17 @@ impl UpscaleTagChecker {
         Ok(contains_tag)
     }
 
+    async fn get_komga_tags(
+        &self,
+        book_id: &str,
+        cookie: Option<Cookie>,
+        auth: Option<Authorization<Basic>>,
+    ) -> Result<Vec<String>, HttpError> {
+        let book = self.komga.get_book(book_id, cookie.clone(), auth.clone()).await?;
+        let series = self.komga.get_series(&book.series_id, cookie, auth).await?;
+        Ok(book.metadata.tags.into_iter().chain(series.metadata.tags).collect())
+    }
+
     async fn check_kavita_tags(
         &self,
         upscale_tag: &String,
@@ -98,6 +138,7 @@ impl UpscaleTagChecker {
     }
 
     pub fn invalidate_cache(&self) {
-        self.cache.invalidate_all()
+        self.cache.invalidate_all();
+        self.profile_cache.invalidate_all()
     }
 }
\ No newline at end of file
@@ -0,0 +1,40 @@
+use std::fs;
+use std::time::{Duration, SystemTime};
+
+use axum_server::tls_rustls::RustlsConfig;
+use log::{error, info};
+
+use crate::config::app_config::TlsConfig;
+
+pub async fn load(config: &TlsConfig) -> Option<RustlsConfig> {
+    match RustlsConfig::from_pem_file(&config.cert_path, &config.key_path).await {
+        Ok(rustls_config) => Some(rustls_config),
+        Err(err) => {
+            error!("can't load tls certificate: {}", err);
+            None
+        }
+    }
+}
+
+// files are polled instead of watched so that renewals through replaced symlinks are also picked up
+pub async fn watch_certificate(rustls_config: RustlsConfig, config: TlsConfig) {
+    let mut last_modified = modified(&config);
+    let mut interval = tokio::time::interval(Duration::from_secs(config.reload_interval.max(1)));
+    loop {
+        interval.tick().await;
+        let modified = modified(&config);
+        if modified == last_modified { continue; }
+
+        match rustls_config.reload_from_pem_file(&config.cert_path, &config.key_path).await {
+            Ok(_) => info!("reloaded tls certificate"),
+            Err(err) => error!("can't reload tls certificate: {}", err)
+        }
+        last_modified = modified;
+    }
+}
+
+fn modified(config: &TlsConfig) -> Option<(SystemTime, SystemTime)> {
+    let cert = fs::metadata(&config.cert_path).and_then(|metadata| metadata.modified()).ok()?;
+    let key = fs::metadata(&config.key_path).and_then(|metadata| metadata.modified()).ok()?;
+    Some((cert, key))
+}
@@ -0,0 +1,182 @@
+use std::io::Cursor;
+
+use image::{AnimationDecoder, Frame, Frames, ImageDecoder, ImageError};
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::codecs::webp::WebPDecoder;
+
+use crate::config::app_config::{LimitsConfig, WebPEncoderConfig};
+use crate::models::errors::UpscaleError;
+use crate::upscaler::image_type::ImageType;
+use crate::upscaler::limits;
+
+pub fn is_animated(input: &[u8], image_type: ImageType) -> bool {
+    match image_type {
+        ImageType::Gif => GifDecoder::new(Cursor::new(input))
+            .map(|decoder| decoder.into_frames().take(2).count() > 1)
+            .unwrap_or(false),
+        ImageType::WebP => WebPDecoder::new(Cursor::new(input))
+            .map(|decoder| decoder.has_animation())
+            .unwrap_or(false),
+        _ => false
+    }
+}
+
+// frames are returned composited to full canvas size. None if animation has more than max_frames frames.
+// decoding stops as soon as frame or memory limit is exceeded
+pub fn decode_frames(input: &[u8], image_type: ImageType, max_frames: u32, limits: &LimitsConfig) -> Result<Option<Vec<Frame>>, UpscaleError> {
+    let frames: Frames = match image_type {
+        ImageType::Gif => GifDecoder::new(Cursor::new(input))
+            .and_then(|mut decoder| decoder.set_limits(limits::image_limits(limits)).map(|_| decoder))
+            .map(|decoder| decoder.into_frames()),
+        ImageType::WebP => WebPDecoder::new(Cursor::new(input))
+            .and_then(|mut decoder| decoder.set_limits(limits::image_limits(limits)).map(|_| decoder))
+            .map(|decoder| decoder.into_frames()),
+        _ => return Err(UpscaleError { message: format!("{:?} images can't be animated", image_type) })
+    }.map_err(|err: ImageError| UpscaleError { message: err.to_string() })?;
+
+    let max_alloc = limits.max_alloc_mb * 1024 * 1024;
+    let mut allocated = 0u64;
+    let mut decoded = Vec::new();
+    for frame in frames.take(max_frames as usize + 1) {
+        if decoded.len() == max_frames as usize {
+            return Ok(None);
+        }
+        let frame = frame.map_err(|err| UpscaleError { message: err.to_string() })?;
+        allocated += frame.buffer().as_raw().len() as u64;
+        if allocated > max_alloc {
+            return Err(UpscaleError { message: format!("decoded animation frames exceed memory limit {} MB", limits.max_alloc_mb) });
+        }
+        decoded.push(frame);
+    }
+
+    Ok(Some(decoded))
+}
+
+// upscaled frames are kept in memory together with decoded frames that are not upscaled yet
+pub fn upscale_memory(frames: &[Frame], scale: u32) -> u64 {
+    let decoded: u64 = frames.iter().map(|frame| frame.buffer().as_raw().len() as u64).sum();
+    decoded + decoded * scale as u64 * scale as u64
+}
+
+// only gif and webp support animation. every other output type falls back to gif
+pub fn animated_type(return_type: ImageType) -> ImageType {
+    match return_type {
+        ImageType::WebP => ImageType::WebP,
+        _ => ImageType::Gif
+    }
+}
+
+pub fn encode_frames(frames: Vec<Frame>, image_type: ImageType, config: &WebPEncoderConfig) -> Result<Vec<u8>, UpscaleError> {
+    match image_type {
+        ImageType::WebP => encode_webp(&frames, config),
+        _ => encode_gif(frames)
+    }
+}
+
+fn encode_gif(frames: Vec<Frame>) -> Result<Vec<u8>, UpscaleError> {
+    let mut buf = Vec::new();
+    {
+        let mut encoder = GifEncoder::new_with_speed(&mut buf, 10);
+        encoder.set_repeat(Repeat::Infinite)
+            .and_then(|_| encoder.encode_frames(frames))
+            .map_err(|err| UpscaleError { message: err.to_string() })?;
+    }
+
+    Ok(buf)
+}
+
+fn encode_webp(frames: &[Frame], config: &WebPEncoderConfig) -> Result<Vec<u8>, UpscaleError> {
+    let first = frames.first()
+        .ok_or_else(|| UpscaleError { message: "animation has no frames".to_string() })?;
+    let (width, height) = first.buffer().dimensions();
+
+    let mut webp_config = webp::WebPConfig::new()
+        .map_err(|_| UpscaleError { message: "can't initialize webp encoder config".to_string() })?;
+    webp_config.lossless = config.lossless as i32;
+    webp_config.quality = config.quality as f32;
+
+    let mut encoder = webp::AnimEncoder::new(width, height, &webp_config);
+    encoder.set_loop_count(0);
+
+    // webp frames are positioned by start timestamp instead of duration
+    let mut timestamp = 0;
+    for frame in frames {
+        encoder.add_frame(webp::AnimFrame::from_rgba(frame.buffer(), width, height, timestamp));
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        timestamp += (numer / denom.max(1)) as i32;
+    }
+
+    let mut encoded = encoder.try_encode()
+        .map(|encoded| encoded.to_vec())
+        .map_err(|err| UpscaleError { message: format!("{:?}", err) })?;
+    set_end_timestamp(&mut encoded, timestamp as u32);
+    Ok(encoded)
+}
+
+// webp encoder finishes animation without end timestamp and last frame loses its duration.
+// duration of last ANMF chunk is set to the time left until the end of animation.
+// encoder can merge identical frames so previous frame durations are summed instead of using last frame delay
+fn set_end_timestamp(webp: &mut [u8], end_timestamp: u32) {
+    let mut offset = 12;
+    let mut elapsed = 0u32;
+    let mut last_frame = None;
+    while offset + 8 <= webp.len() {
+        let size = u32::from_le_bytes([webp[offset + 4], webp[offset + 5], webp[offset + 6], webp[offset + 7]]) as usize;
+        if &webp[offset..offset + 4] == b"ANMF" && offset + 8 + 16 <= webp.len() {
+            let duration_offset = offset + 8 + 12;
+            if let Some(previous) = last_frame {
+                elapsed += read_u24(webp, previous);
+            }
+            last_frame = Some(duration_offset);
+        }
+        offset += 8 + size + size % 2;
+    }
+
+    if let Some(duration_offset) = last_frame {
+        let duration = end_timestamp.saturating_sub(elapsed).min(0xFFFFFF);
+        webp[duration_offset..duration_offset + 3].copy_from_slice(&duration.to_le_bytes()[..3]);
+    }
+}
+
+fn read_u24(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], 0])
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Delay, RgbaImage};
+
+    use super::*;
+
+    fn frame(color: u8, delay_ms: u32) -> Frame {
+        let buffer = RgbaImage::from_pixel(8, 8, image::Rgba([color, color, color, 255]));
+        Frame::from_parts(buffer, 0, 0, Delay::from_numer_denom_ms(delay_ms, 1))
+    }
+
+    fn frame_delays(webp: &[u8]) -> Vec<u32> {
+        WebPDecoder::new(Cursor::new(webp)).unwrap()
+            .into_frames().collect_frames().unwrap()
+            .iter()
+            .map(|frame| frame.delay().numer_denom_ms().0)
+            .collect()
+    }
+
+    #[test]
+    fn upscale_memory_counts_upscaled_frames() {
+        assert_eq!(upscale_memory(&[frame(0, 100), frame(255, 100)], 2), 2 * 8 * 8 * 4 * 5);
+    }
+
+    #[test]
+    fn webp_keeps_last_frame_duration() {
+        let config = WebPEncoderConfig { lossless: true, quality: 90 };
+        let encoded = encode_webp(&[frame(0, 100), frame(255, 250)], &config).unwrap();
+        assert_eq!(frame_delays(&encoded), vec![100, 250]);
+    }
+
+    #[test]
+    fn webp_keeps_total_duration_of_merged_frames() {
+        let config = WebPEncoderConfig { lossless: true, quality: 90 };
+        let encoded = encode_webp(&[frame(0, 100), frame(255, 200), frame(255, 300)], &config).unwrap();
+        assert_eq!(frame_delays(&encoded).iter().sum::<u32>(), 600);
+    }
+}
@@ -0,0 +1,117 @@
+use std::io::Cursor;
+
+use image::{DynamicImage, GrayAlphaImage, GrayImage, RgbaImage, RgbImage};
+use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+use crate::config::app_config::LimitsConfig;
+use crate::models::errors::UpscaleError;
+use crate::upscaler::image_type::ImageType;
+use crate::upscaler::jxl_header;
+use crate::upscaler::limits::image_limits;
+
+pub fn decode(input: &[u8], image_type: ImageType, limits: &LimitsConfig) -> Result<DynamicImage, UpscaleError> {
+    match image_type {
+        ImageType::Jxl => decode_jxl(input, limits),
+        ImageType::Avif | ImageType::Heif => decode_heif(input, limits),
+        _ => {
+            let mut reader = image::io::Reader::new(Cursor::new(input));
+            if let Some(image_format) = image_type.image_format() {
+                reader.set_format(image_format);
+            }
+            reader.limits(image_limits(limits));
+            reader.decode()
+                .or_else(|_| {
+                    let mut reader = image::io::Reader::new(Cursor::new(input))
+                        .with_guessed_format()
+                        .map_err(|err| UpscaleError { message: err.to_string() })?;
+                    reader.limits(image_limits(limits));
+                    reader.decode().map_err(|err| UpscaleError { message: err.to_string() })
+                })
+        }
+    }
+}
+
+// reads only image header
+pub fn dimensions(input: &[u8], image_type: ImageType) -> Result<(u32, u32), UpscaleError> {
+    match image_type {
+        ImageType::Jxl => jxl_header::dimensions(input)
+            .ok_or_else(|| UpscaleError { message: "invalid jxl header".to_string() }),
+        ImageType::Avif | ImageType::Heif => {
+            let context = HeifContext::read_from_bytes(input)
+                .map_err(|err| UpscaleError { message: err.to_string() })?;
+            let handle = context.primary_image_handle()
+                .map_err(|err| UpscaleError { message: err.to_string() })?;
+            Ok((handle.width(), handle.height()))
+        }
+        _ => {
+            let mut reader = image::io::Reader::new(Cursor::new(input));
+            if let Some(image_format) = image_type.image_format() {
+                reader.set_format(image_format);
+            }
+            reader.into_dimensions()
+                .map_err(|err| UpscaleError { message: err.to_string() })
+        }
+    }
+}
+
+fn decode_jxl(input: &[u8], limits: &LimitsConfig) -> Result<DynamicImage, UpscaleError> {
+    let (width, height) = dimensions(input, ImageType::Jxl)?;
+    // decoder keeps f32 samples internally next to u8 output
+    check_alloc(width as u64 * height as u64 * 4 * 5, limits)?;
+
+    let decoder = jpegxl_rs::decoder_builder().build()
+        .map_err(|err| UpscaleError { message: err.to_string() })?;
+    let (metadata, pixels) = decoder.decode_with::<u8>(input)
+        .map_err(|err| UpscaleError { message: err.to_string() })?;
+
+    let (width, height) = (metadata.width, metadata.height);
+    let image = match metadata.num_color_channels + metadata.has_alpha_channel as u32 {
+        1 => GrayImage::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8),
+        2 => GrayAlphaImage::from_raw(width, height, pixels).map(DynamicImage::ImageLumaA8),
+        3 => RgbImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8),
+        4 => RgbaImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgba8),
+        channels => return Err(UpscaleError { message: format!("unsupported jxl channel count {}", channels) })
+    };
+    image.ok_or_else(|| UpscaleError { message: "invalid jxl frame buffer".to_string() })
+}
+
+fn decode_heif(input: &[u8], limits: &LimitsConfig) -> Result<DynamicImage, UpscaleError> {
+    let context = HeifContext::read_from_bytes(input)
+        .map_err(|err| UpscaleError { message: err.to_string() })?;
+    let handle = context.primary_image_handle()
+        .map_err(|err| UpscaleError { message: err.to_string() })?;
+    let has_alpha = handle.has_alpha_channel();
+    // decoded plane and copy without row padding
+    let channels = if has_alpha { 4 } else { 3 };
+    check_alloc(handle.width() as u64 * handle.height() as u64 * channels * 2, limits)?;
+    let chroma = if has_alpha { RgbChroma::Rgba } else { RgbChroma::Rgb };
+
+    let image = LibHeif::new().decode(&handle, ColorSpace::Rgb(chroma), None)
+        .map_err(|err| UpscaleError { message: err.to_string() })?;
+    let plane = image.planes().interleaved
+        .ok_or_else(|| UpscaleError { message: "heif image has no interleaved plane".to_string() })?;
+
+    // rows can be padded, copy only the pixel data
+    let row_length = plane.width as usize * channels as usize;
+    let pixels: Vec<u8> = plane.data.chunks(plane.stride)
+        .take(plane.height as usize)
+        .flat_map(|row| &row[..row_length])
+        .copied()
+        .collect();
+
+    let image = if has_alpha {
+        RgbaImage::from_raw(plane.width, plane.height, pixels).map(DynamicImage::ImageRgba8)
+    } else {
+        RgbImage::from_raw(plane.width, plane.height, pixels).map(DynamicImage::ImageRgb8)
+    };
+    image.ok_or_else(|| UpscaleError { message: "invalid heif image plane".to_string() })
+}
+
+// jxl and heif decoders don't support image crate limits. allocation is estimated before decoding
+fn check_alloc(bytes: u64, limits: &LimitsConfig) -> Result<(), UpscaleError> {
+    let max_alloc = limits.max_alloc_mb * 1024 * 1024;
+    if bytes > max_alloc {
+        return Err(UpscaleError { message: format!("decoding requires {} MB which exceeds limit {} MB", bytes / 1024 / 1024, limits.max_alloc_mb) });
+    }
+    Ok(())
+}
@@ -0,0 +1,134 @@
+use std::io::Cursor;
+
+use image::DynamicImage;
+use image::codecs::avif::AvifEncoder;
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use jpeg_encoder::SamplingFactor;
+use jpegxl_rs::encode::EncoderSpeed;
+
+use crate::config::app_config::{AvifEncoderConfig, ChromaSubsampling, EncoderConfig, JpegEncoderConfig, JxlEncoderConfig, PngCompression, PngEncoderConfig, WebPEncoderConfig};
+use crate::models::errors::UpscaleError;
+use crate::upscaler::image_type::ImageType;
+
+pub fn encode(image: &DynamicImage, image_type: ImageType, config: &EncoderConfig) -> Result<Vec<u8>, UpscaleError> {
+    match image_type {
+        ImageType::Jpeg => encode_jpeg(image, &config.jpeg),
+        ImageType::WebP => encode_webp(image, &config.webp),
+        ImageType::Png => encode_png(image, &config.png),
+        ImageType::Avif => encode_avif(image, &config.avif),
+        ImageType::Jxl => encode_jxl(image, &config.jxl),
+        _ => {
+            let format = image_type.image_format()
+                .ok_or_else(|| UpscaleError { message: format!("no encoder for {:?}", image_type) })?;
+            let mut buf = Cursor::new(Vec::new());
+            image.write_to(&mut buf, format)
+                .map_err(|err| UpscaleError { message: err.to_string() })?;
+            Ok(buf.into_inner())
+        }
+    }
+}
+
+fn encode_jpeg(image: &DynamicImage, config: &JpegEncoderConfig) -> Result<Vec<u8>, UpscaleError> {
+    let width = u16::try_from(image.width())
+        .map_err(|_| UpscaleError { message: format!("image width {} is too big for jpeg", image.width()) })?;
+    let height = u16::try_from(image.height())
+        .map_err(|_| UpscaleError { message: format!("image height {} is too big for jpeg", image.height()) })?;
+
+    let mut buf = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut buf, config.quality);
+    encoder.set_sampling_factor(match config.chroma_subsampling {
+        ChromaSubsampling::Yuv444 => SamplingFactor::R_4_4_4,
+        ChromaSubsampling::Yuv422 => SamplingFactor::R_4_2_2,
+        ChromaSubsampling::Yuv420 => SamplingFactor::R_4_2_0,
+    });
+
+    let result = if image.color().has_color() {
+        encoder.encode(image.to_rgb8().as_raw(), width, height, jpeg_encoder::ColorType::Rgb)
+    } else {
+        encoder.encode(image.to_luma8().as_raw(), width, height, jpeg_encoder::ColorType::Luma)
+    };
+    result.map_err(|err| UpscaleError { message: err.to_string() })?;
+
+    Ok(buf)
+}
+
+const WEBP_MAX_DIMENSION: u32 = 16383;
+
+fn encode_webp(image: &DynamicImage, config: &WebPEncoderConfig) -> Result<Vec<u8>, UpscaleError> {
+    let (width, height) = (image.width(), image.height());
+    if width > WEBP_MAX_DIMENSION || height > WEBP_MAX_DIMENSION {
+        return Err(UpscaleError { message: format!("image size {}x{} is too big for webp", width, height) });
+    }
+
+    let encode = |encoder: webp::Encoder| encoder.encode_simple(config.lossless, config.quality as f32)
+        .map(|encoded| encoded.to_vec())
+        .map_err(|err| UpscaleError { message: format!("webp encoding error {:?}", err) });
+
+    if image.color().has_alpha() {
+        let pixels = image.to_rgba8();
+        encode(webp::Encoder::from_rgba(&pixels, width, height))
+    } else {
+        let pixels = image.to_rgb8();
+        encode(webp::Encoder::from_rgb(&pixels, width, height))
+    }
+}
+
+fn encode_png(image: &DynamicImage, config: &PngEncoderConfig) -> Result<Vec<u8>, UpscaleError> {
+    let compression = match config.compression {
+        PngCompression::Fast => CompressionType::Fast,
+        PngCompression::Default => CompressionType::Default,
+        PngCompression::Best => CompressionType::Best,
+    };
+
+    let mut buf = Vec::new();
+    image.write_with_encoder(PngEncoder::new_with_quality(&mut buf, compression, FilterType::Adaptive))
+        .map_err(|err| UpscaleError { message: err.to_string() })?;
+
+    if !config.optimize {
+        return Ok(buf);
+    }
+
+    oxipng::optimize_from_memory(&buf, &oxipng::Options::from_preset(config.optimize_level))
+        .map_err(|err| UpscaleError { message: err.to_string() })
+}
+
+fn encode_avif(image: &DynamicImage, config: &AvifEncoderConfig) -> Result<Vec<u8>, UpscaleError> {
+    let mut buf = Vec::new();
+    image.write_with_encoder(AvifEncoder::new_with_speed_quality(&mut buf, config.speed, config.quality))
+        .map_err(|err| UpscaleError { message: err.to_string() })?;
+
+    Ok(buf)
+}
+
+fn encode_jxl(image: &DynamicImage, config: &JxlEncoderConfig) -> Result<Vec<u8>, UpscaleError> {
+    let speed = match config.effort {
+        0 | 1 => EncoderSpeed::Lightning,
+        2 => EncoderSpeed::Thunder,
+        3 => EncoderSpeed::Falcon,
+        4 => EncoderSpeed::Cheetah,
+        5 => EncoderSpeed::Hare,
+        6 => EncoderSpeed::Wombat,
+        7 => EncoderSpeed::Squirrel,
+        8 => EncoderSpeed::Kitten,
+        _ => EncoderSpeed::Tortoise,
+    };
+    let has_alpha = image.color().has_alpha();
+
+    let mut encoder = jpegxl_rs::encoder_builder()
+        .speed(speed)
+        .quality(config.distance)
+        .lossless(config.lossless)
+        .has_alpha(has_alpha)
+        .build()
+        .map_err(|err| UpscaleError { message: err.to_string() })?;
+
+    let encoded = if has_alpha {
+        encoder.encode::<u8, u8>(image.to_rgba8().as_raw(), image.width(), image.height())
+    } else {
+        encoder.encode::<u8, u8>(image.to_rgb8().as_raw(), image.width(), image.height())
+    };
+
+    encoded
+        .map(|result| result.data)
+        .map_err(|err| UpscaleError { message: err.to_string() })
+}
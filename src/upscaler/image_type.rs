@@ -0,0 +1,125 @@
+use image::ImageFormat;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ImageType {
+    Png,
+    Jpeg,
+    WebP,
+    Gif,
+    Bmp,
+    Tiff,
+    Avif,
+    Jxl,
+    Heif,
+}
+
+impl ImageType {
+    pub fn from_mime_type(mime_type: &str) -> Option<Self> {
+        let mime_type = mime_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        match mime_type.as_str() {
+            "image/png" => Some(ImageType::Png),
+            "image/jpeg" | "image/jpg" => Some(ImageType::Jpeg),
+            "image/webp" => Some(ImageType::WebP),
+            "image/gif" => Some(ImageType::Gif),
+            "image/bmp" => Some(ImageType::Bmp),
+            "image/tiff" => Some(ImageType::Tiff),
+            "image/avif" => Some(ImageType::Avif),
+            "image/jxl" => Some(ImageType::Jxl),
+            "image/heif" | "image/heic" | "image/heif-sequence" | "image/heic-sequence" => Some(ImageType::Heif),
+            _ => None
+        }
+    }
+
+    pub fn from_magic_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(ImageType::Png)
+        } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(ImageType::Jpeg)
+        } else if bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+            Some(ImageType::WebP)
+        } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+            Some(ImageType::Gif)
+        } else if bytes.starts_with(b"BM") {
+            Some(ImageType::Bmp)
+        } else if bytes.starts_with(b"II*\0") || bytes.starts_with(b"MM\0*") {
+            Some(ImageType::Tiff)
+        } else if bytes.starts_with(&[0xFF, 0x0A]) || bytes.starts_with(b"\0\0\0\x0cJXL \r\n\x87\n") {
+            Some(ImageType::Jxl)
+        } else if bytes.len() >= 12 && &bytes[4..8] == b"ftyp" {
+            Self::from_ftyp_box(bytes)
+        } else {
+            None
+        }
+    }
+
+    // ISOBMFF container. major brand followed by minor version and compatible brands
+    fn from_ftyp_box(bytes: &[u8]) -> Option<Self> {
+        let box_size = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+        let box_end = box_size.clamp(12, bytes.len());
+        let compatible_brands = bytes.get(16..box_end).unwrap_or_default().chunks_exact(4);
+        let brands: Vec<&[u8]> = std::iter::once(&bytes[8..12]).chain(compatible_brands).collect();
+
+        if brands.iter().any(|brand| *brand == b"avif" || *brand == b"avis") {
+            Some(ImageType::Avif)
+        } else if brands.iter().any(|brand| matches!(*brand, b"heic" | b"heix" | b"hevc" | b"hevx" | b"heim" | b"heis" | b"mif1" | b"msf1")) {
+            Some(ImageType::Heif)
+        } else {
+            None
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            ImageType::Png => "image/png",
+            ImageType::Jpeg => "image/jpeg",
+            ImageType::WebP => "image/webp",
+            ImageType::Gif => "image/gif",
+            ImageType::Bmp => "image/bmp",
+            ImageType::Tiff => "image/tiff",
+            ImageType::Avif => "image/avif",
+            ImageType::Jxl => "image/jxl",
+            ImageType::Heif => "image/heif",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageType::Png => "png",
+            ImageType::Jpeg => "jpeg",
+            ImageType::WebP => "webp",
+            ImageType::Gif => "gif",
+            ImageType::Bmp => "bmp",
+            ImageType::Tiff => "tiff",
+            ImageType::Avif => "avif",
+            ImageType::Jxl => "jxl",
+            ImageType::Heif => "heic",
+        }
+    }
+
+    // format used by image crate codecs. None if the type is handled outside of image crate
+    pub fn image_format(&self) -> Option<ImageFormat> {
+        match self {
+            ImageType::Png => Some(ImageFormat::Png),
+            ImageType::Jpeg => Some(ImageFormat::Jpeg),
+            ImageType::WebP => Some(ImageFormat::WebP),
+            ImageType::Gif => Some(ImageFormat::Gif),
+            ImageType::Bmp => Some(ImageFormat::Bmp),
+            ImageType::Tiff => Some(ImageFormat::Tiff),
+            ImageType::Avif => Some(ImageFormat::Avif),
+            ImageType::Jxl | ImageType::Heif => None,
+        }
+    }
+
+    // formats without built in compression
+    pub fn is_uncompressed(&self) -> bool {
+        matches!(self, ImageType::Bmp | ImageType::Tiff)
+    }
+
+    // there is no heif encoder. avif uses the same container and is the closest alternative
+    pub fn closest_encodable(&self) -> ImageType {
+        match self {
+            ImageType::Heif => ImageType::Avif,
+            image_type => *image_type
+        }
+    }
+}
@@ -0,0 +1,103 @@
+const CODESTREAM_SIGNATURE: [u8; 2] = [0xFF, 0x0A];
+const CONTAINER_SIGNATURE: [u8; 12] = [0, 0, 0, 0x0C, b'J', b'X', b'L', b' ', 0x0D, 0x0A, 0x87, 0x0A];
+
+// reads size header of jxl codestream without decoding the image
+pub fn dimensions(input: &[u8]) -> Option<(u32, u32)> {
+    let codestream = if input.starts_with(&CONTAINER_SIGNATURE) {
+        container_codestream(input)?
+    } else {
+        input
+    };
+    let mut bits = BitReader { bytes: codestream.strip_prefix(&CODESTREAM_SIGNATURE)?, position: 0 };
+
+    let small = bits.read(1)? == 1;
+    let height = if small { (bits.read(5)? + 1) * 8 } else { bits.read_size()? };
+    let width = match bits.read(3)? {
+        0 if small => (bits.read(5)? + 1) * 8,
+        0 => bits.read_size()?,
+        ratio => {
+            let (numerator, denominator) = [(1, 1), (12, 10), (4, 3), (3, 2), (16, 9), (5, 4), (2, 1)][ratio as usize - 1];
+            u32::try_from(height as u64 * numerator / denominator).ok()?
+        }
+    };
+    Some((width, height))
+}
+
+// first codestream box of the container. partial codestream boxes start with 4 byte index
+fn container_codestream(input: &[u8]) -> Option<&[u8]> {
+    let mut boxes = input;
+    while boxes.len() >= 8 {
+        let size = u32::from_be_bytes(boxes[0..4].try_into().ok()?) as u64;
+        let box_type = &boxes[4..8];
+        let (header_size, size) = match size {
+            0 => (8, boxes.len() as u64),
+            1 => (16, u64::from_be_bytes(boxes.get(8..16)?.try_into().ok()?)),
+            size => (8, size)
+        };
+        let size = usize::try_from(size).ok()?.min(boxes.len());
+        let payload = boxes.get(header_size..size)?;
+        match box_type {
+            b"jxlc" => return Some(payload),
+            b"jxlp" => return payload.get(4..),
+            _ => boxes = &boxes[size.max(header_size)..]
+        }
+    }
+    None
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl BitReader<'_> {
+    // bits are packed starting from the least significant bit
+    fn read(&mut self, count: usize) -> Option<u32> {
+        let mut value = 0u32;
+        for i in 0..count {
+            let byte = self.bytes.get(self.position / 8)?;
+            value |= (((byte >> (self.position % 8)) & 1) as u32) << i;
+            self.position += 1;
+        }
+        Some(value)
+    }
+
+    fn read_size(&mut self) -> Option<u32> {
+        let bits = [9, 13, 18, 30][self.read(2)? as usize];
+        Some(self.read(bits)? + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_small_size() {
+        assert_eq!(dimensions(&[0xFF, 0x0A, 0x41, 0x00]), Some((8, 8)));
+    }
+
+    #[test]
+    fn reads_explicit_size() {
+        assert_eq!(dimensions(&[0xFF, 0x0A, 0x18, 0x83, 0x9E, 0x0F]), Some((2000, 100)));
+    }
+
+    #[test]
+    fn reads_size_from_ratio() {
+        assert_eq!(dimensions(&[0xFF, 0x0A, 0xFC, 0x69, 0xF8]), Some((400000, 200000)));
+    }
+
+    #[test]
+    fn reads_size_from_container() {
+        let mut input = CONTAINER_SIGNATURE.to_vec();
+        input.extend_from_slice(&[0, 0, 0, 20, b'f', b't', b'y', b'p', b'j', b'x', b'l', b' ', 0, 0, 0, 0, b'j', b'x', b'l', b' ']);
+        input.extend_from_slice(&[0, 0, 0, 12, b'j', b'x', b'l', b'c', 0xFF, 0x0A, 0x41, 0x00]);
+        assert_eq!(dimensions(&input), Some((8, 8)));
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        assert_eq!(dimensions(&[0xFF, 0x0A, 0x18]), None);
+        assert_eq!(dimensions(&[0x89, 0x50]), None);
+    }
+}
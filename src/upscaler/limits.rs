@@ -0,0 +1,24 @@
+use image::io::Limits;
+
+use crate::config::app_config::LimitsConfig;
+
+pub fn image_limits(config: &LimitsConfig) -> Limits {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(config.max_width);
+    limits.max_image_height = Some(config.max_height);
+    limits.max_alloc = Some(config.max_alloc_mb * 1024 * 1024);
+    limits
+}
+
+// checked before decoding. returns reason if image should not be upscaled
+pub fn check(width: u32, height: u32, scale: u32, config: &LimitsConfig) -> Result<(), String> {
+    if width > config.max_width || height > config.max_height {
+        return Err(format!("image dimensions {}x{} exceed limit {}x{}", width, height, config.max_width, config.max_height));
+    }
+
+    let output_pixels = width as u64 * height as u64 * scale as u64 * scale as u64;
+    if output_pixels > config.max_output_pixels {
+        return Err(format!("upscaled image size {} pixels exceeds limit {}", output_pixels, config.max_output_pixels));
+    }
+    Ok(())
+}
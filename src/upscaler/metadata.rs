@@ -0,0 +1,97 @@
+use bytes::Bytes;
+use exif::{In, Tag};
+use image::DynamicImage;
+use img_parts::{DynImage, ImageEXIF, ImageICC};
+use log::warn;
+
+use crate::models::errors::UpscaleError;
+use crate::upscaler::image_type::ImageType;
+
+pub struct ImageMetadata {
+    icc_profile: Option<Bytes>,
+    orientation: u32,
+}
+
+impl ImageMetadata {
+    // only jpeg, png and webp containers are inspected.
+    // heif and jxl decoders apply orientation on their own
+    pub fn read(input: &Bytes) -> Self {
+        let container = match DynImage::from_bytes(input.clone()) {
+            Ok(Some(container)) => container,
+            _ => return Self { icc_profile: None, orientation: 1 }
+        };
+
+        let orientation = container.exif()
+            .and_then(|exif| exif::Reader::new().read_raw(exif.to_vec()).ok())
+            .and_then(|exif| exif.get_field(Tag::Orientation, In::PRIMARY)
+                .and_then(|field| field.value.get_uint(0))
+            )
+            .unwrap_or(1);
+
+        Self { icc_profile: container.icc_profile(), orientation }
+    }
+
+    pub fn apply_orientation(&self, image: DynamicImage) -> DynamicImage {
+        match self.orientation {
+            2 => image.fliph(),
+            3 => image.rotate180(),
+            4 => image.flipv(),
+            5 => image.rotate90().fliph(),
+            6 => image.rotate90(),
+            7 => image.rotate270().fliph(),
+            8 => image.rotate270(),
+            _ => image
+        }
+    }
+
+    // jpeg and png keep grayscale images in single channel, webp is always encoded as rgb
+    pub fn embed_icc_profile(&self, encoded: Vec<u8>, image_type: ImageType, has_color: bool) -> Result<Vec<u8>, UpscaleError> {
+        let icc_profile = match &self.icc_profile {
+            None => return Ok(encoded),
+            Some(icc_profile) => icc_profile.clone()
+        };
+        if !matches!(image_type, ImageType::Jpeg | ImageType::Png | ImageType::WebP) {
+            warn!("can't embed icc profile into {:?} image. colors might be shifted", image_type);
+            return Ok(encoded);
+        }
+        let gray = !has_color && matches!(image_type, ImageType::Jpeg | ImageType::Png);
+        if !matches_color_space(&icc_profile, gray) {
+            warn!("icc profile color space doesn't match {:?} image. colors might be shifted", image_type);
+            return Ok(encoded);
+        }
+
+        let mut container = DynImage::from_bytes(Bytes::from(encoded))
+            .map_err(|err| UpscaleError { message: err.to_string() })?
+            .ok_or_else(|| UpscaleError { message: "unsupported image container".to_string() })?;
+        container.set_icc_profile(Some(icc_profile));
+
+        Ok(container.encoder().bytes().to_vec())
+    }
+}
+
+// color space signature of icc profile header. cmyk and lab profiles can't describe rgb or gray output
+fn matches_color_space(icc_profile: &[u8], gray: bool) -> bool {
+    let expected: &[u8] = if gray { b"GRAY" } else { b"RGB " };
+    icc_profile.get(16..20) == Some(expected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(color_space: &[u8; 4]) -> Vec<u8> {
+        let mut profile = vec![0; 128];
+        profile[16..20].copy_from_slice(color_space);
+        profile
+    }
+
+    #[test]
+    fn profile_color_space_must_match_output() {
+        assert!(matches_color_space(&profile(b"RGB "), false));
+        assert!(matches_color_space(&profile(b"GRAY"), true));
+        assert!(!matches_color_space(&profile(b"GRAY"), false));
+        assert!(!matches_color_space(&profile(b"RGB "), true));
+        assert!(!matches_color_space(&profile(b"CMYK"), false));
+        assert!(!matches_color_space(&[0; 10], false));
+    }
+}
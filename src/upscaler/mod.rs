@@ -1,2 +1,15 @@
 pub mod upscaler;
-pub mod upscale_actor;
\ No newline at end of file
+pub mod upscale_actor;
+pub mod noise_detection;
+pub mod encoder;
+pub mod image_type;
+pub mod decoder;
+pub mod jxl_header;
+pub mod metadata;
+pub mod animation;
+pub mod tiling;
+pub mod spread;
+pub mod preprocess;
+pub mod postprocess;
+pub mod quality_guard;
+pub mod limits;
\ No newline at end of file
@@ -0,0 +1,225 @@
+use log::info;
+
+use crate::config::app_config::NoiseDetectionConfig;
+use crate::upscaler::image_type::ImageType;
+
+// zigzag position -> natural (row-major) position of the DCT coefficient
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10,
+    17, 24, 32, 25, 18, 11, 4, 5,
+    12, 19, 26, 33, 40, 48, 41, 34,
+    27, 20, 13, 6, 7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36,
+    29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46,
+    53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+// libjpeg reference luminance table (quality 50), natural order
+const STD_LUMINANCE_TABLE: [u16; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61,
+    12, 12, 14, 19, 26, 58, 60, 55,
+    14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62,
+    18, 22, 37, 56, 68, 109, 103, 77,
+    24, 35, 55, 64, 81, 104, 113, 92,
+    49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103, 99,
+];
+
+// libjpeg reference chrominance table (quality 50), natural order
+const STD_CHROMINANCE_TABLE: [u16; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99,
+    18, 21, 26, 66, 99, 99, 99, 99,
+    24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+pub fn detect_noise_level(config: &NoiseDetectionConfig, image: &[u8], image_type: ImageType) -> Option<i32> {
+    if !config.enabled { return None; }
+
+    match image_type {
+        ImageType::Jpeg => {
+            let quality = estimate_jpeg_quality(image)?;
+            let noise = config.jpeg_quality_noise.iter()
+                .filter(|level| quality >= level.min_quality)
+                .max_by_key(|level| level.min_quality)
+                .map(|level| level.noise)?;
+            info!("estimated jpeg quality {}. using noise level {}", quality, noise);
+            Some(noise)
+        }
+        ImageType::WebP if !is_lossless_webp(image) => None,
+        ImageType::Png | ImageType::WebP | ImageType::Gif | ImageType::Bmp | ImageType::Tiff => {
+            Some(config.lossless_noise)
+        }
+        _ => None
+    }
+}
+
+// inverse of libjpeg quality scaling applied to the average ratio between image and reference tables
+pub fn estimate_jpeg_quality(image: &[u8]) -> Option<u32> {
+    let tables = read_quantization_tables(image);
+    let (table, reference) = tables.iter()
+        .find(|(id, _)| *id == 0)
+        .map(|(_, table)| (table, &STD_LUMINANCE_TABLE))
+        .or_else(|| tables.iter()
+            .find(|(id, _)| *id == 1)
+            .map(|(_, table)| (table, &STD_CHROMINANCE_TABLE))
+        )?;
+
+    let scale_sum: f64 = table.iter().enumerate()
+        .map(|(zigzag_index, value)| {
+            let reference_value = reference[ZIGZAG[zigzag_index]] as f64;
+            *value as f64 * 100.0 / reference_value
+        })
+        .sum();
+    let scale = scale_sum / 64.0;
+
+    let quality = if scale <= 100.0 { (200.0 - scale) / 2.0 } else { 5000.0 / scale };
+    Some(quality.round().clamp(1.0, 100.0) as u32)
+}
+
+fn read_quantization_tables(image: &[u8]) -> Vec<(u8, [u16; 64])> {
+    let mut tables = Vec::new();
+    if image.len() < 4 || image[0] != 0xFF || image[1] != 0xD8 {
+        return tables;
+    }
+
+    let mut pos = 2;
+    while pos + 4 <= image.len() {
+        if image[pos] != 0xFF {
+            break;
+        }
+        let marker = image[pos + 1];
+        match marker {
+            // fill byte
+            0xFF => {
+                pos += 1;
+                continue;
+            }
+            // standalone markers without length
+            0x01 | 0xD0..=0xD7 => {
+                pos += 2;
+                continue;
+            }
+            // start of scan or end of image. quantization tables must be defined before
+            0xDA | 0xD9 => break,
+            _ => {}
+        }
+
+        let length = u16::from_be_bytes([image[pos + 2], image[pos + 3]]) as usize;
+        let segment_end = (pos + 2 + length).min(image.len());
+        if marker == 0xDB {
+            let mut table_pos = pos + 4;
+            while table_pos < segment_end {
+                let precision = image[table_pos] >> 4;
+                let id = image[table_pos] & 0x0F;
+                let entry_size = if precision == 0 { 1 } else { 2 };
+                table_pos += 1;
+                if table_pos + 64 * entry_size > segment_end {
+                    break;
+                }
+
+                let mut table = [0u16; 64];
+                for (i, value) in table.iter_mut().enumerate() {
+                    let entry = table_pos + i * entry_size;
+                    *value = if entry_size == 1 {
+                        image[entry] as u16
+                    } else {
+                        u16::from_be_bytes([image[entry], image[entry + 1]])
+                    };
+                }
+                tables.push((id, table));
+                table_pos += 64 * entry_size;
+            }
+        }
+        pos += 2 + length;
+    }
+
+    tables
+}
+
+fn is_lossless_webp(image: &[u8]) -> bool {
+    if image.len() < 12 || &image[0..4] != b"RIFF" || &image[8..12] != b"WEBP" {
+        return false;
+    }
+
+    let mut pos = 12;
+    while pos + 8 <= image.len() {
+        let chunk = &image[pos..pos + 4];
+        if chunk == b"VP8L" { return true; }
+        if chunk == b"VP8 " { return false; }
+
+        let size = u32::from_le_bytes([image[pos + 4], image[pos + 5], image[pos + 6], image[pos + 7]]) as usize;
+        // chunks are padded to even size
+        pos += 8 + size + (size & 1);
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, RgbImage};
+    use image::codecs::jpeg::JpegEncoder;
+
+    use super::*;
+
+    // quantization table segment with reference tables scaled like libjpeg does for quality
+    fn jpeg_with_table(id: u8, reference: &[u16; 64], quality: u32, precision: u8) -> Vec<u8> {
+        let scale = if quality < 50 { 5000 / quality } else { 200 - quality * 2 };
+        let entry_size = if precision == 0 { 1 } else { 2 };
+
+        let mut image = vec![0xFF, 0xD8, 0xFF, 0xDB];
+        image.extend_from_slice(&((2 + 1 + 64 * entry_size) as u16).to_be_bytes());
+        image.push(precision << 4 | id);
+        for natural_index in ZIGZAG {
+            let value = ((reference[natural_index] as u32 * scale + 50) / 100).clamp(1, 255) as u16;
+            if precision == 0 {
+                image.push(value as u8);
+            } else {
+                image.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+        image.extend_from_slice(&[0xFF, 0xD9]);
+        image
+    }
+
+    #[test]
+    fn estimates_quality_of_8_bit_tables() {
+        assert_eq!(estimate_jpeg_quality(&jpeg_with_table(0, &STD_LUMINANCE_TABLE, 50, 0)), Some(50));
+        assert_eq!(estimate_jpeg_quality(&jpeg_with_table(0, &STD_LUMINANCE_TABLE, 25, 0)), Some(25));
+    }
+
+    #[test]
+    fn estimates_quality_of_16_bit_tables() {
+        assert_eq!(estimate_jpeg_quality(&jpeg_with_table(0, &STD_LUMINANCE_TABLE, 50, 1)), Some(50));
+    }
+
+    #[test]
+    fn falls_back_to_chrominance_table() {
+        assert_eq!(estimate_jpeg_quality(&jpeg_with_table(1, &STD_CHROMINANCE_TABLE, 50, 0)), Some(50));
+    }
+
+    #[test]
+    fn estimates_quality_of_encoded_image() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(16, 16, |x, y| image::Rgb([x as u8 * 16, y as u8 * 16, 128])));
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, 90).encode_image(&image).unwrap();
+
+        let quality = estimate_jpeg_quality(&jpeg).unwrap();
+        assert!((88..=92).contains(&quality), "estimated quality {}", quality);
+    }
+
+    #[test]
+    fn ignores_truncated_and_non_jpeg_input() {
+        let jpeg = jpeg_with_table(0, &STD_LUMINANCE_TABLE, 50, 1);
+        assert_eq!(estimate_jpeg_quality(&jpeg[..40]), None);
+        assert_eq!(estimate_jpeg_quality(b"\x89PNG\r\n\x1a\n"), None);
+        assert_eq!(estimate_jpeg_quality(&[]), None);
+    }
+}
@@ -0,0 +1,72 @@
+use image::{DynamicImage, Rgba, RgbaImage};
+use image::imageops::FilterType;
+
+use crate::config::app_config::PostprocessStep;
+
+// steps are applied in configured order. source dimensions are dimensions before upscaling
+pub fn postprocess(image: DynamicImage, steps: &[PostprocessStep], source_dimensions: (u32, u32)) -> DynamicImage {
+    steps.iter().fold(image, |image, step| match step {
+        PostprocessStep::Sharpen { sigma, threshold } => image.unsharpen(*sigma, *threshold),
+        PostprocessStep::Levels { black_point, white_point } => levels(image, *black_point, *white_point),
+        PostprocessStep::Deband { radius, threshold } => deband(image, *radius, *threshold),
+        PostprocessStep::Downscale { scale } => downscale(image, *scale, source_dimensions),
+    })
+}
+
+fn levels(image: DynamicImage, black_point: u8, white_point: u8) -> DynamicImage {
+    if white_point <= black_point {
+        return image;
+    }
+
+    let range = (white_point - black_point) as f32;
+    let lookup: Vec<u8> = (0..=255u8)
+        .map(|value| ((value.saturating_sub(black_point) as f32 / range).min(1.0) * 255.0).round() as u8)
+        .collect();
+
+    map_color_channels(image, |value| lookup[value as usize])
+}
+
+// smooth gradients are replaced with blurred values. edges and details above threshold are kept
+fn deband(image: DynamicImage, radius: f32, threshold: u8) -> DynamicImage {
+    let original = image.to_rgba8();
+    let blurred = image.blur(radius).to_rgba8();
+
+    let debanded = RgbaImage::from_fn(original.width(), original.height(), |x, y| {
+        let (pixel, smooth) = (original.get_pixel(x, y), blurred.get_pixel(x, y));
+        let is_flat = (0..3).all(|channel| pixel[channel].abs_diff(smooth[channel]) <= threshold);
+        if is_flat { Rgba([smooth[0], smooth[1], smooth[2], pixel[3]]) } else { *pixel }
+    });
+
+    restore_color_type(&image, debanded)
+}
+
+fn downscale(image: DynamicImage, scale: f32, source_dimensions: (u32, u32)) -> DynamicImage {
+    let width = ((source_dimensions.0 as f32 * scale).round() as u32).max(1);
+    let height = ((source_dimensions.1 as f32 * scale).round() as u32).max(1);
+    if width >= image.width() || height >= image.height() {
+        return image;
+    }
+
+    image.resize_exact(width, height, FilterType::Lanczos3)
+}
+
+fn map_color_channels(image: DynamicImage, map: impl Fn(u8) -> u8) -> DynamicImage {
+    let mut pixels = image.to_rgba8();
+    for pixel in pixels.pixels_mut() {
+        for channel in 0..3 {
+            pixel[channel] = map(pixel[channel]);
+        }
+    }
+
+    restore_color_type(&image, pixels)
+}
+
+fn restore_color_type(original: &DynamicImage, pixels: RgbaImage) -> DynamicImage {
+    let pixels = DynamicImage::ImageRgba8(pixels);
+    match (original.color().has_color(), original.color().has_alpha()) {
+        (true, true) => pixels,
+        (true, false) => DynamicImage::ImageRgb8(pixels.to_rgb8()),
+        (false, true) => DynamicImage::ImageLumaA8(pixels.to_luma_alpha8()),
+        (false, false) => DynamicImage::ImageLuma8(pixels.to_luma8()),
+    }
+}
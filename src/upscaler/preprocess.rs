@@ -0,0 +1,127 @@
+use image::{DynamicImage, GrayImage, Rgba, RgbaImage};
+use image::imageops::FilterType;
+use log::info;
+
+use crate::config::app_config::PreprocessConfig;
+
+const DESKEW_SAMPLE_WIDTH: u32 = 800;
+const DESKEW_ANGLE_STEP: f32 = 0.25;
+const DESKEW_MIN_ANGLE: f32 = 0.2;
+
+pub fn preprocess(image: DynamicImage, config: &PreprocessConfig) -> DynamicImage {
+    let mut image = image;
+    if config.deskew {
+        image = deskew(image, config.deskew_max_angle);
+    }
+    if config.trim_margins {
+        image = trim_margins(image, config.trim_tolerance);
+    }
+    if config.descreen && config.descreen_sigma > 0.0 {
+        image = image.blur(config.descreen_sigma);
+    }
+    image
+}
+
+// margins are the outer area that matches top left pixel color within tolerance
+fn trim_margins(image: DynamicImage, tolerance: u8) -> DynamicImage {
+    let gray = image.to_luma8();
+    let background = gray.get_pixel(0, 0)[0];
+    let is_content = |x: u32, y: u32| gray.get_pixel(x, y)[0].abs_diff(background) > tolerance;
+
+    let (width, height) = gray.dimensions();
+    let row_has_content = |y: u32| (0..width).any(|x| is_content(x, y));
+    let column_has_content = |x: u32, top: u32, bottom: u32| (top..=bottom).any(|y| is_content(x, y));
+
+    let top = match (0..height).find(|y| row_has_content(*y)) {
+        Some(top) => top,
+        None => return image
+    };
+    let bottom = (top..height).rev().find(|y| row_has_content(*y)).unwrap_or(top);
+    let left = (0..width).find(|x| column_has_content(*x, top, bottom)).unwrap_or(0);
+    let right = (left..width).rev().find(|x| column_has_content(*x, top, bottom)).unwrap_or(left);
+
+    let (trimmed_width, trimmed_height) = (right - left + 1, bottom - top + 1);
+    if trimmed_width == width && trimmed_height == height {
+        return image;
+    }
+    info!("trimming margins {}x{} -> {}x{}", width, height, trimmed_width, trimmed_height);
+    image.crop_imm(left, top, trimmed_width, trimmed_height)
+}
+
+fn deskew(image: DynamicImage, max_angle: f32) -> DynamicImage {
+    let angle = estimate_skew(&image, max_angle);
+    if angle.abs() < DESKEW_MIN_ANGLE {
+        return image;
+    }
+
+    info!("deskewing image by {} degrees", angle);
+    let rotated = DynamicImage::ImageRgba8(rotate(&image.to_rgba8(), angle.to_radians()));
+    if image.color().has_alpha() { rotated } else { DynamicImage::ImageRgb8(rotated.to_rgb8()) }
+}
+
+// projection profile. rows of dark pixels line up best at the correct angle
+fn estimate_skew(image: &DynamicImage, max_angle: f32) -> f32 {
+    let sample = if image.width() > DESKEW_SAMPLE_WIDTH {
+        let height = (image.height() as u64 * DESKEW_SAMPLE_WIDTH as u64 / image.width() as u64).max(1) as u32;
+        image.resize_exact(DESKEW_SAMPLE_WIDTH, height, FilterType::Triangle).to_luma8()
+    } else {
+        image.to_luma8()
+    };
+
+    let steps = (max_angle / DESKEW_ANGLE_STEP).floor() as i32;
+    (-steps..=steps)
+        .map(|step| step as f32 * DESKEW_ANGLE_STEP)
+        .map(|angle| (angle, projection_score(&sample, angle)))
+        .fold((0.0, 0u64), |best, candidate| if candidate.1 > best.1 { candidate } else { best })
+        .0
+}
+
+fn projection_score(image: &GrayImage, angle: f32) -> u64 {
+    let (width, height) = image.dimensions();
+    let shear = angle.to_radians().tan();
+    let offset = (width as f32 * shear.abs()).ceil() as i64;
+    let mut rows = vec![0u64; (height as i64 + offset * 2 + 1) as usize];
+
+    for (x, y, pixel) in image.enumerate_pixels() {
+        if pixel[0] < 128 {
+            let row = (y as f32 + x as f32 * shear).round() as i64 + offset;
+            rows[row as usize] += 1;
+        }
+    }
+    rows.iter().map(|count| count * count).sum()
+}
+
+// uncovered corners are filled with top left pixel color
+fn rotate(image: &RgbaImage, angle: f32) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    let (center_x, center_y) = (width as f32 / 2.0, height as f32 / 2.0);
+    let (sin, cos) = angle.sin_cos();
+    let background = *image.get_pixel(0, 0);
+
+    RgbaImage::from_fn(width, height, |x, y| {
+        let (dx, dy) = (x as f32 - center_x, y as f32 - center_y);
+        let source_x = center_x + dx * cos + dy * sin;
+        let source_y = center_y - dx * sin + dy * cos;
+        sample_bilinear(image, source_x, source_y).unwrap_or(background)
+    })
+}
+
+fn sample_bilinear(image: &RgbaImage, x: f32, y: f32) -> Option<Rgba<u8>> {
+    let (width, height) = image.dimensions();
+    if x < 0.0 || y < 0.0 || x > (width - 1) as f32 || y > (height - 1) as f32 {
+        return None;
+    }
+
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+    let (p00, p10, p01, p11) = (image.get_pixel(x0, y0), image.get_pixel(x1, y0), image.get_pixel(x0, y1), image.get_pixel(x1, y1));
+    let mut pixel = [0u8; 4];
+    for channel in 0..4 {
+        let top = p00[channel] as f32 * (1.0 - fx) + p10[channel] as f32 * fx;
+        let bottom = p01[channel] as f32 * (1.0 - fx) + p11[channel] as f32 * fx;
+        pixel[channel] = (top * (1.0 - fy) + bottom * fy).round() as u8;
+    }
+    Some(Rgba(pixel))
+}
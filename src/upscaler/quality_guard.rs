@@ -0,0 +1,66 @@
+use image::{DynamicImage, GrayImage};
+use image::imageops::FilterType;
+use log::info;
+
+use crate::config::app_config::QualityGuardConfig;
+
+const SSIM_BLOCK_SIZE: u32 = 8;
+const SSIM_C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+const SSIM_C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+// upscaled image is downscaled back to source size and compared with the source
+pub fn is_acceptable(source: &DynamicImage, upscaled: &DynamicImage, config: &QualityGuardConfig) -> bool {
+    let source = source.to_luma8();
+    let downscaled = upscaled.resize_exact(source.width(), source.height(), FilterType::Triangle).to_luma8();
+
+    let psnr = psnr(&source, &downscaled);
+    let ssim = ssim(&source, &downscaled);
+    if psnr < config.min_psnr || ssim < config.min_ssim {
+        info!("upscaled image rejected by quality guard. psnr: {:.2} ssim: {:.3}", psnr, ssim);
+        return false;
+    }
+    true
+}
+
+fn psnr(source: &GrayImage, other: &GrayImage) -> f64 {
+    let squared_error: f64 = source.pixels().zip(other.pixels())
+        .map(|(a, b)| (a[0] as f64 - b[0] as f64).powi(2))
+        .sum();
+    let mse = squared_error / (source.width() as f64 * source.height() as f64).max(1.0);
+    if mse == 0.0 { return f64::INFINITY; }
+
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
+
+// mean ssim over non overlapping blocks
+fn ssim(source: &GrayImage, other: &GrayImage) -> f64 {
+    let (width, height) = source.dimensions();
+    let mut total = 0.0;
+    let mut blocks = 0;
+
+    for block_y in (0..height).step_by(SSIM_BLOCK_SIZE as usize) {
+        for block_x in (0..width).step_by(SSIM_BLOCK_SIZE as usize) {
+            let pixels: Vec<(f64, f64)> = (block_y..(block_y + SSIM_BLOCK_SIZE).min(height))
+                .flat_map(|y| (block_x..(block_x + SSIM_BLOCK_SIZE).min(width)).map(move |x| (x, y)))
+                .map(|(x, y)| (source.get_pixel(x, y)[0] as f64, other.get_pixel(x, y)[0] as f64))
+                .collect();
+            let count = pixels.len() as f64;
+
+            let mean_a = pixels.iter().map(|(a, _)| a).sum::<f64>() / count;
+            let mean_b = pixels.iter().map(|(_, b)| b).sum::<f64>() / count;
+            let (mut variance_a, mut variance_b, mut covariance) = (0.0, 0.0, 0.0);
+            for (a, b) in &pixels {
+                variance_a += (a - mean_a).powi(2);
+                variance_b += (b - mean_b).powi(2);
+                covariance += (a - mean_a) * (b - mean_b);
+            }
+            let (variance_a, variance_b, covariance) = (variance_a / count, variance_b / count, covariance / count);
+
+            total += ((2.0 * mean_a * mean_b + SSIM_C1) * (2.0 * covariance + SSIM_C2))
+                / ((mean_a.powi(2) + mean_b.powi(2) + SSIM_C1) * (variance_a + variance_b + SSIM_C2));
+            blocks += 1;
+        }
+    }
+
+    if blocks == 0 { 1.0 } else { total / blocks as f64 }
+}
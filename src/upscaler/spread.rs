@@ -0,0 +1,63 @@
+use image::DynamicImage;
+use image::imageops::FilterType;
+
+use crate::config::app_config::SpreadConfig;
+
+const GUTTER_MAX_DEVIATION: f32 = 12.0;
+
+pub fn is_spread(image: &DynamicImage, config: &SpreadConfig) -> bool {
+    if image.height() == 0 { return false; }
+
+    let aspect_ratio = image.width() as f32 / image.height() as f32;
+    aspect_ratio >= config.min_aspect_ratio && (!config.gutter_check || has_gutter(image))
+}
+
+// gutter is a near uniform column close to the horizontal center
+fn has_gutter(image: &DynamicImage) -> bool {
+    let strip_width = (image.width() / 25).max(1);
+    let strip = image
+        .crop_imm((image.width() - strip_width) / 2, 0, strip_width, image.height())
+        .to_luma8();
+
+    (0..strip.width()).any(|x| {
+        let column: Vec<f32> = (0..strip.height())
+            .map(|y| strip.get_pixel(x, y)[0] as f32)
+            .collect();
+        let mean = column.iter().sum::<f32>() / column.len() as f32;
+        let variance = column.iter().map(|value| (value - mean).powi(2)).sum::<f32>() / column.len() as f32;
+        variance.sqrt() < GUTTER_MAX_DEVIATION
+    })
+}
+
+pub fn split(image: &DynamicImage) -> (DynamicImage, DynamicImage) {
+    let half = image.width() / 2;
+    (
+        image.crop_imm(0, 0, half, image.height()),
+        image.crop_imm(half, 0, image.width() - half, image.height())
+    )
+}
+
+pub fn join(left: &DynamicImage, right: &DynamicImage) -> DynamicImage {
+    let width = left.width() + right.width();
+    let height = left.height().max(right.height());
+    let mut joined = if left.color().has_alpha() || right.color().has_alpha() {
+        DynamicImage::new_rgba8(width, height)
+    } else {
+        DynamicImage::new_rgb8(width, height)
+    };
+
+    image::imageops::replace(&mut joined, left, 0, 0);
+    image::imageops::replace(&mut joined, right, left.width() as i64, 0);
+    joined
+}
+
+pub fn fit_half_width(image: DynamicImage, max_half_width: u32) -> DynamicImage {
+    let half_width = image.width() / 2;
+    if half_width <= max_half_width {
+        return image;
+    }
+
+    let ratio = max_half_width as f32 / half_width as f32;
+    let height = ((image.height() as f32 * ratio).round() as u32).max(1);
+    image.resize_exact(max_half_width * 2, height, FilterType::Lanczos3)
+}
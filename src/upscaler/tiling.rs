@@ -0,0 +1,44 @@
+use image::{DynamicImage, RgbaImage};
+
+use crate::config::app_config::TilingConfig;
+
+// (start, height) of every band. consecutive bands share `overlap` rows
+pub fn bands(height: u32, config: &TilingConfig) -> Vec<(u32, u32)> {
+    let band_height = config.band_height.max(1);
+    if height <= band_height {
+        return vec![(0, height)];
+    }
+
+    let overlap = config.overlap.min(band_height / 2);
+    let step = band_height - overlap;
+    let mut bands = Vec::new();
+    let mut start = 0;
+    while start + band_height < height {
+        bands.push((start, band_height));
+        start += step;
+    }
+    bands.push((start, height - start));
+    bands
+}
+
+pub fn crop_band(image: &DynamicImage, start: u32, height: u32) -> DynamicImage {
+    image.crop_imm(0, start, image.width(), height)
+}
+
+// overlapping rows are linearly faded from previous band into the current one to hide seams
+pub fn blend_band(output: &mut RgbaImage, band: &RgbaImage, start: u32, overlap: u32) {
+    let width = output.width().min(band.width());
+    let height = band.height().min(output.height().saturating_sub(start));
+
+    for y in 0..height {
+        let weight = if y < overlap { (y as f32 + 0.5) / overlap as f32 } else { 1.0 };
+        for x in 0..width {
+            let source = band.get_pixel(x, y);
+            let target = output.get_pixel_mut(x, start + y);
+            for channel in 0..4 {
+                let blended = target[channel] as f32 * (1.0 - weight) + source[channel] as f32 * weight;
+                target[channel] = blended.round() as u8;
+            }
+        }
+    }
+}
@@ -1,23 +1,25 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use bytes::Bytes;
-use image::ImageFormat;
 use log::{error, info};
 use ractor::{Actor, ActorProcessingErr, ActorRef, RpcReplyPort, SupervisionEvent};
 
-use EnabledUpscaler::{Realcugan, Waifu2x};
+use EnabledUpscaler::{Chain, Realcugan, Waifu2x};
 
 use crate::config::app_config::{AppConfig, EnabledUpscaler};
-use crate::upscaler::upscaler::{RealCuganUpscaler, Upscaler, Waifu2xUpscaler};
+use crate::models::errors::UpscaleError;
+use crate::upscaler::image_type::ImageType;
+use crate::upscaler::upscaler::{ChainUpscaler, RealCuganUpscaler, UpscaledImage, Upscaler, Waifu2xUpscaler};
 
 pub enum UpscaleSupervisorMessage {
-    Upscale(Bytes, ImageFormat, RpcReplyPort<(Bytes, ImageFormat)>),
+    Upscale(Bytes, ImageType, ImageType, Option<String>, RpcReplyPort<Result<UpscaledImage, UpscaleError>>),
     Init(Arc<AppConfig>),
     Destroy,
 }
 
 pub enum UpscaleMessage {
-    Upscale(Bytes, ImageFormat, RpcReplyPort<(Bytes, ImageFormat)>),
+    Upscale(Bytes, ImageType, ImageType, Option<String>, RpcReplyPort<Result<UpscaledImage, UpscaleError>>),
 }
 
 pub struct UpscaleSupervisorActor;
@@ -39,12 +41,12 @@ impl Actor for UpscaleSupervisorActor {
 
     async fn handle(&self, myself: ActorRef<Self>, message: Self::Msg, state: &mut Self::State) -> Result<(), ActorProcessingErr> {
         match message {
-            UpscaleSupervisorMessage::Upscale(image, format, reply_to) => {
+            UpscaleSupervisorMessage::Upscale(image, image_type, return_type, profile, reply_to) => {
                 match &state.upscale_actor {
                     None => { return Err(From::from("Upscale Actor is not Initialized")); }
                     Some(upscale_actor) => {
                         let _ = upscale_actor
-                            .send_message(UpscaleMessage::Upscale(image, format, reply_to));
+                            .send_message(UpscaleMessage::Upscale(image, image_type, return_type, profile, reply_to));
                     }
                 }
             }
@@ -103,25 +105,39 @@ impl Actor for UpscaleSupervisorActor {
 
 pub struct UpscaleActor;
 
+pub struct UpscaleActorState {
+    upscaler: Box<dyn Upscaler>,
+    profiles: HashMap<String, Box<dyn Upscaler>>,
+}
+
 #[async_trait::async_trait]
 impl Actor for UpscaleActor {
     type Msg = UpscaleMessage;
-    type State = Box<dyn Upscaler>;
+    type State = UpscaleActorState;
     type Arguments = Arc<AppConfig>;
 
     async fn pre_start(&self, _myself: ActorRef<Self>, args: Self::Arguments) -> Result<Self::State, ActorProcessingErr> {
         let upscaler: Box<dyn Upscaler> = match args.upscaler {
             Waifu2x => Box::new(Waifu2xUpscaler::new(args.clone())),
-            Realcugan => Box::new(RealCuganUpscaler::new(args.clone()))
+            Realcugan => Box::new(RealCuganUpscaler::new(args.clone())),
+            Chain => Box::new(ChainUpscaler::new(args.clone(), &args.chain))
         };
-
-        Ok(upscaler)
+        let profiles = args.profiles.iter()
+            .map(|profile| {
+                let upscaler: Box<dyn Upscaler> = Box::new(ChainUpscaler::for_profile(&args, profile));
+                (profile.name.clone(), upscaler)
+            })
+            .collect();
+
+        Ok(UpscaleActorState { upscaler, profiles })
     }
 
     async fn handle(&self, _myself: ActorRef<Self>, message: Self::Msg, state: &mut Self::State) -> Result<(), ActorProcessingErr> {
         match message {
-            UpscaleMessage::Upscale(image, format, reply_to) => {
-                let _ = reply_to.send(state.upscale(image, format));
+            UpscaleMessage::Upscale(image, image_type, return_type, profile, reply_to) => {
+                let upscaler = profile.and_then(|profile| state.profiles.get(&profile))
+                    .unwrap_or(&state.upscaler);
+                let _ = reply_to.send(upscaler.upscale(image, image_type, return_type));
             }
         }
 
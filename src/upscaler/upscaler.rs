@@ -1,89 +1,277 @@
-use std::io::Cursor;
+use std::collections::{BTreeMap, BTreeSet};
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
 
 use bytes::Bytes;
-use image::{DynamicImage, ImageFormat};
-use log::info;
-use realcugan_ncnn_vulkan_rs::RealCugan;
+use image::{DynamicImage, Frame, GrayImage, RgbaImage};
+use image::imageops::FilterType;
+use log::{info, warn};
+use realcugan_ncnn_vulkan_rs::{RealCugan, RealCuganModelType};
 use waifu2x_ncnn_vulkan_rs::Waifu2x;
 
-use crate::config::app_config::{AppConfig, Format};
+use crate::config::app_config::{AnimationConfig, AnimationMode, AppConfig, ChainStage, EncoderConfig, LimitsConfig, NoiseDetectionConfig, PostprocessStep, PreprocessConfig, QualityGuardConfig, SpreadConfig, TilingConfig, UpscaleProfile};
+use crate::metrics::QUALITY_GUARD_REJECTIONS;
+use crate::models::errors::UpscaleError;
+use crate::upscaler::{animation, decoder, encoder, limits, quality_guard, spread, tiling};
+use crate::upscaler::postprocess::postprocess;
+use crate::upscaler::preprocess::preprocess;
+use crate::upscaler::image_type::ImageType;
+use crate::upscaler::metadata::ImageMetadata;
+use crate::upscaler::noise_detection::detect_noise_level;
 
-#[derive(Copy, Clone)]
+pub struct UpscaledImage {
+    pub bytes: Bytes,
+    pub image_type: ImageType,
+    pub spread: bool,
+    pub upscaled: bool,
+}
+
+#[derive(Clone)]
 pub struct UpscalerConfig {
     threshold_enabled: bool,
     threshold: u32,
     threshold_png: u32,
-    return_format: Format,
+    encoder: EncoderConfig,
+    noise: i32,
+    scale: u32,
+    noise_detection: NoiseDetectionConfig,
+    animation: AnimationConfig,
+    tiling: TilingConfig,
+    spreads: SpreadConfig,
+    preprocess: PreprocessConfig,
+    postprocess: Vec<PostprocessStep>,
+    quality_guard: QualityGuardConfig,
+    limits: LimitsConfig,
 }
 
 pub trait Upscaler: Send {
-    fn upscale(&self, input: Bytes, image_format: ImageFormat) -> (Bytes, ImageFormat) {
+    fn upscale(&self, input: Bytes, image_type: ImageType, return_type: ImageType) -> Result<UpscaledImage, UpscaleError> {
         let config = self.get_config();
         if config.threshold_enabled {
             let input_kb = (input.len() / 1024) as u32;
-            let threshold = if image_format == ImageFormat::Png { config.threshold_png } else { config.threshold };
+            let threshold = if image_type == ImageType::Png { config.threshold_png } else { config.threshold };
             if input_kb > threshold {
                 info!("image size {} is bigger than threshold {}. skipping upscale", input_kb, threshold);
-                return (input, image_format);
+                return Ok(UpscaledImage::original(input, image_type));
+            }
+        }
+
+        let (width, height) = decoder::dimensions(&input, image_type)?;
+        if let Err(reason) = limits::check(width, height, config.scale, &config.limits) {
+            warn!("{}. skipping upscale", reason);
+            return Ok(UpscaledImage::original(input, image_type));
+        }
+
+        let noise = detect_noise_level(&config.noise_detection, &input, image_type)
+            .unwrap_or(config.noise);
+
+        if animation::is_animated(&input, image_type) {
+            return self.upscale_animation(input, image_type, return_type, noise);
+        }
+
+        let metadata = ImageMetadata::read(&input);
+        let image = metadata.apply_orientation(decoder::decode(&input, image_type, &config.limits)?);
+        let image = preprocess(image, &config.preprocess);
+        let source_dimensions = (image.width(), image.height());
+        let spread = config.spreads.enabled && spread::is_spread(&image, &config.spreads);
+        let source = config.quality_guard.enabled.then(|| image.clone());
+        let upscaled = if spread {
+            self.upscale_spread(image, noise)
+        } else {
+            self.upscale_tiled(image, noise)
+        };
+        if let Some(source) = source {
+            if !quality_guard::is_acceptable(&source, &upscaled, &config.quality_guard) {
+                QUALITY_GUARD_REJECTIONS.fetch_add(1, Ordering::Relaxed);
+                return Ok(UpscaledImage::original(input, image_type));
             }
         }
+        let upscaled = postprocess(upscaled, &config.postprocess, source_dimensions);
 
-        let mut reader = image::io::Reader::new(Cursor::new(input.clone()));
-        reader.set_format(image_format);
-        let image = reader.decode()
-            .or(image::io::Reader::new(Cursor::new(input))
-                .with_guessed_format().unwrap().decode()
-            ).unwrap();
-
-        let upscaled = self.upscale_image(image);
-        let mut buf = Cursor::new(Vec::new());
-
-        let format_to = match config.return_format {
-            Format::Png => { ImageFormat::Png }
-            Format::Jpeg => { ImageFormat::Jpeg }
-            Format::WebP => { ImageFormat::WebP }
-            Format::Original => { image_format }
+        let encoded = encoder::encode(&upscaled, return_type, &config.encoder)?;
+        let encoded = metadata.embed_icc_profile(encoded, return_type, upscaled.color().has_color())?;
+        Ok(UpscaledImage { spread, ..UpscaledImage::new(Bytes::from(encoded), return_type) })
+    }
+
+    fn upscale_animation(&self, input: Bytes, image_type: ImageType, return_type: ImageType, noise: i32) -> Result<UpscaledImage, UpscaleError> {
+        let config = self.get_config();
+        if let AnimationMode::Passthrough = config.animation.mode {
+            info!("animated {:?} image. skipping upscale", image_type);
+            return Ok(UpscaledImage::original(input, image_type));
+        }
+
+        let frames = match animation::decode_frames(&input, image_type, config.animation.max_frames, &config.limits)? {
+            Some(frames) => frames,
+            None => {
+                info!("animation frame count is bigger than limit {}. skipping upscale", config.animation.max_frames);
+                return Ok(UpscaledImage::original(input, image_type));
+            }
         };
+        if animation::upscale_memory(&frames, config.scale) > config.limits.max_alloc_mb * 1024 * 1024 {
+            info!("upscaled animation frames exceed memory limit {} MB. skipping upscale", config.limits.max_alloc_mb);
+            return Ok(UpscaledImage::original(input, image_type));
+        }
+
+        let upscaled: Vec<Frame> = frames.into_iter()
+            .map(|frame| {
+                let delay = frame.delay();
+                let upscaled = self.upscale_with_alpha(DynamicImage::ImageRgba8(frame.into_buffer()), noise);
+                Frame::from_parts(upscaled.to_rgba8(), 0, 0, delay)
+            })
+            .collect();
 
-        upscaled.write_to(&mut buf, format_to).expect("can't write image");
-        (Bytes::from(buf.into_inner()), format_to)
+        let animated_type = animation::animated_type(return_type);
+        let encoded = animation::encode_frames(upscaled, animated_type, &config.encoder.webp)?;
+        Ok(UpscaledImage::new(Bytes::from(encoded), animated_type))
     }
 
-    fn upscale_image(&self, image: DynamicImage) -> DynamicImage;
+    fn upscale_spread(&self, image: DynamicImage, noise: i32) -> DynamicImage {
+        let config = &self.get_config().spreads;
+        info!("double page spread detected");
 
-    fn get_config(&self) -> UpscalerConfig;
+        let upscaled = if config.split_upscale {
+            let (left, right) = spread::split(&image);
+            spread::join(&self.upscale_tiled(left, noise), &self.upscale_tiled(right, noise))
+        } else {
+            self.upscale_tiled(image, noise)
+        };
+
+        match config.max_half_width {
+            Some(max_half_width) => spread::fit_half_width(upscaled, max_half_width),
+            None => upscaled
+        }
+    }
+
+    // tall images are upscaled in overlapping horizontal bands to stay within gpu memory limits
+    fn upscale_tiled(&self, image: DynamicImage, noise: i32) -> DynamicImage {
+        let config = &self.get_config().tiling;
+        let bands = tiling::bands(image.height(), config);
+        if !config.enabled || bands.len() == 1 {
+            return self.upscale_band(image, noise);
+        }
+        info!("splitting {}x{} image into {} bands", image.width(), image.height(), bands.len());
+
+        let mut output: Option<RgbaImage> = None;
+        let mut previous_end = 0u32;
+        for (start, height) in bands {
+            let upscaled = self.upscale_band(tiling::crop_band(&image, start, height), noise).to_rgba8();
+            let scale = (upscaled.width() / image.width()).max(1);
+            let output = output.get_or_insert_with(|| RgbaImage::new(image.width() * scale, image.height() * scale));
+
+            tiling::blend_band(output, &upscaled, start * scale, previous_end.saturating_sub(start) * scale);
+            previous_end = start + height;
+        }
+
+        let output = DynamicImage::ImageRgba8(output.unwrap_or_default());
+        if image.color().has_alpha() { output } else { DynamicImage::ImageRgb8(output.to_rgb8()) }
+    }
+
+    fn upscale_band(&self, image: DynamicImage, noise: i32) -> DynamicImage {
+        if image.color().has_alpha() {
+            self.upscale_with_alpha(image, noise)
+        } else {
+            self.upscale_image(image, noise)
+        }
+    }
+
+    // models work on rgb only. alpha is resized separately and merged back
+    fn upscale_with_alpha(&self, image: DynamicImage, noise: i32) -> DynamicImage {
+        let rgba = image.to_rgba8();
+        let alpha = GrayImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+            image::Luma([rgba.get_pixel(x, y)[3]])
+        });
+
+        let upscaled = self.upscale_image(DynamicImage::ImageRgb8(image.to_rgb8()), noise).to_rgb8();
+        let alpha = image::imageops::resize(&alpha, upscaled.width(), upscaled.height(), FilterType::CatmullRom);
+
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(upscaled.width(), upscaled.height(), |x, y| {
+            let [r, g, b] = upscaled.get_pixel(x, y).0;
+            image::Rgba([r, g, b, alpha.get_pixel(x, y)[0]])
+        }))
+    }
+
+    fn upscale_image(&self, image: DynamicImage, noise: i32) -> DynamicImage;
+
+    fn get_config(&self) -> &UpscalerConfig;
 }
 
 pub struct Waifu2xUpscaler {
     config: UpscalerConfig,
-    waifu2x: Waifu2x,
+    waifu2x: BTreeMap<i32, Waifu2x>,
 }
 
 pub struct RealCuganUpscaler {
     config: UpscalerConfig,
-    realcugan: RealCugan,
+    realcugan: BTreeMap<i32, RealCugan>,
 }
 
-impl Waifu2xUpscaler {
-    pub fn new(config: Arc<AppConfig>) -> Self {
-        let waifu2x = Waifu2x::new(
-            config.waifu2x.gpuid,
-            config.waifu2x.noise,
-            config.waifu2x.scale,
-            config.waifu2x.model,
-            config.waifu2x.tile_size,
-            config.waifu2x.tta_mode,
-            config.waifu2x.num_threads,
-            config.waifu2x.models_path.clone(),
-        );
-
-        let upscaler_config = UpscalerConfig {
+// stages run one after another with their own fixed noise level. detected noise is ignored
+pub struct ChainUpscaler {
+    config: UpscalerConfig,
+    stages: Vec<Box<dyn Upscaler>>,
+}
+
+impl UpscaledImage {
+    fn new(bytes: Bytes, image_type: ImageType) -> Self {
+        Self { bytes, image_type, spread: false, upscaled: true }
+    }
+
+    // input returned unchanged when upscale is skipped or rejected
+    fn original(bytes: Bytes, image_type: ImageType) -> Self {
+        Self { bytes, image_type, spread: false, upscaled: false }
+    }
+}
+
+impl UpscalerConfig {
+    fn new(config: &AppConfig, noise: i32, scale: u32) -> Self {
+        Self {
             threshold_enabled: config.size_threshold_enabled,
             threshold: config.size_threshold,
             threshold_png: config.size_threshold_png,
-            return_format: config.return_format,
-        };
+            encoder: config.encoder.clone(),
+            noise,
+            scale,
+            noise_detection: config.noise_detection.clone(),
+            animation: config.animation.clone(),
+            tiling: config.tiling.clone(),
+            spreads: config.spreads.clone(),
+            preprocess: config.preprocess.clone(),
+            postprocess: config.postprocess.clone(),
+            quality_guard: config.quality_guard.clone(),
+            limits: config.limits.clone(),
+        }
+    }
+
+    // every noise level that can be requested needs its own model instance
+    fn noise_levels(&self) -> BTreeSet<i32> {
+        let mut levels = BTreeSet::from([self.noise]);
+        if self.noise_detection.enabled {
+            levels.insert(self.noise_detection.lossless_noise);
+            levels.extend(self.noise_detection.jpeg_quality_noise.iter().map(|level| level.noise));
+        }
+        levels
+    }
+}
+
+impl Waifu2xUpscaler {
+    pub fn new(config: Arc<AppConfig>) -> Self {
+        let upscaler_config = UpscalerConfig::new(&config, config.waifu2x.noise, config.waifu2x.scale);
+
+        let waifu2x = upscaler_config.noise_levels().into_iter()
+            .map(|noise| {
+                let model = Waifu2x::new(
+                    config.waifu2x.gpuid,
+                    noise,
+                    config.waifu2x.scale,
+                    config.waifu2x.model,
+                    config.waifu2x.tile_size,
+                    config.waifu2x.tta_mode,
+                    config.waifu2x.num_threads,
+                    config.waifu2x.models_path.clone(),
+                );
+                (noise, model)
+            })
+            .collect();
 
         Self { config: upscaler_config, waifu2x }
     }
@@ -91,24 +279,36 @@ impl Waifu2xUpscaler {
 
 impl RealCuganUpscaler {
     pub fn new(config: Arc<AppConfig>) -> Self {
-        let realcugan = RealCugan::new(
-            config.realcugan.gpuid,
-            config.realcugan.noise,
-            config.realcugan.scale,
-            config.realcugan.model,
-            config.realcugan.tile_size,
-            config.realcugan.sync_gap,
-            config.realcugan.tta_mode,
-            config.realcugan.num_threads,
-            config.realcugan.models_path.clone(),
-        );
-
-        let upscaler_config = UpscalerConfig {
-            threshold_enabled: config.size_threshold_enabled,
-            threshold: config.size_threshold,
-            threshold_png: config.size_threshold_png,
-            return_format: config.return_format,
-        };
+        let upscaler_config = UpscalerConfig::new(&config, config.realcugan.noise, config.realcugan.scale);
+
+        let supported = realcugan_noise_levels(config.realcugan.model, config.realcugan.scale);
+        let noise_levels: BTreeSet<i32> = upscaler_config.noise_levels().into_iter()
+            .map(|noise| {
+                let level = closest_noise_level(noise, supported.iter().copied());
+                if level != noise {
+                    warn!("realcugan {:?} model has no noise level {} for scale {}. using noise level {}",
+                        config.realcugan.model, noise, config.realcugan.scale, level);
+                }
+                level
+            })
+            .collect();
+
+        let realcugan = noise_levels.into_iter()
+            .map(|noise| {
+                let model = RealCugan::new(
+                    config.realcugan.gpuid,
+                    noise,
+                    config.realcugan.scale,
+                    config.realcugan.model,
+                    config.realcugan.tile_size,
+                    config.realcugan.sync_gap,
+                    config.realcugan.tta_mode,
+                    config.realcugan.num_threads,
+                    config.realcugan.models_path.clone(),
+                );
+                (noise, model)
+            })
+            .collect();
 
         Self {
             config: upscaler_config,
@@ -117,23 +317,101 @@ impl RealCuganUpscaler {
     }
 }
 
+// real-cugan ships denoise1x and denoise2x models only for se model at 2x scale
+fn realcugan_noise_levels(model: RealCuganModelType, scale: u32) -> &'static [i32] {
+    match (model, scale) {
+        (RealCuganModelType::Se, 2) => &[-1, 0, 1, 2, 3],
+        (RealCuganModelType::Se, _) | (RealCuganModelType::Pro, _) => &[-1, 0, 3],
+        (RealCuganModelType::Nose, _) => &[0],
+    }
+}
+
+// ties are resolved towards stronger denoise
+fn closest_noise_level(noise: i32, levels: impl Iterator<Item=i32>) -> i32 {
+    levels.min_by_key(|level| ((level - noise).abs(), -level)).unwrap_or(noise)
+}
+
+impl ChainUpscaler {
+    pub fn new(config: Arc<AppConfig>, stages: &[ChainStage]) -> Self {
+        let scale = stages.iter()
+            .map(|stage| match stage {
+                ChainStage::Waifu2x { scale, .. } | ChainStage::Realcugan { scale, .. } => *scale,
+                ChainStage::Dejpeg { .. } => 1,
+            })
+            .product();
+        let upscaler_config = UpscalerConfig::new(&config, -1, scale);
+
+        let stages = stages.iter()
+            .map(|stage| {
+                let mut stage_config = (*config).clone();
+                stage_config.noise_detection.enabled = false;
+                let upscaler: Box<dyn Upscaler> = match stage {
+                    ChainStage::Waifu2x { noise, scale } => {
+                        stage_config.waifu2x.noise = *noise;
+                        stage_config.waifu2x.scale = *scale;
+                        Box::new(Waifu2xUpscaler::new(Arc::new(stage_config)))
+                    }
+                    ChainStage::Realcugan { noise, scale } => {
+                        stage_config.realcugan.noise = *noise;
+                        stage_config.realcugan.scale = *scale;
+                        Box::new(RealCuganUpscaler::new(Arc::new(stage_config)))
+                    }
+                    // jpeg artifact removal runs waifu2x denoise without scaling with models from dejpeg section
+                    ChainStage::Dejpeg { noise } => {
+                        stage_config.waifu2x.noise = *noise;
+                        stage_config.waifu2x.scale = 1;
+                        stage_config.waifu2x.model = config.dejpeg.model;
+                        stage_config.waifu2x.models_path = config.dejpeg.models_path.clone();
+                        Box::new(Waifu2xUpscaler::new(Arc::new(stage_config)))
+                    }
+                };
+                upscaler
+            })
+            .collect();
+
+        Self { config: upscaler_config, stages }
+    }
+
+    pub fn for_profile(config: &AppConfig, profile: &UpscaleProfile) -> Self {
+        let mut profile_config = config.clone();
+        if let Some(preprocess) = &profile.preprocess {
+            profile_config.preprocess = preprocess.clone();
+        }
+        Self::new(Arc::new(profile_config), &profile.chain)
+    }
+}
 
 impl Upscaler for Waifu2xUpscaler {
-    fn upscale_image(&self, image: DynamicImage) -> DynamicImage {
-        self.waifu2x.proc_image(image)
+    fn upscale_image(&self, image: DynamicImage, noise: i32) -> DynamicImage {
+        let waifu2x = self.waifu2x.get(&noise)
+            .unwrap_or_else(|| &self.waifu2x[&self.config.noise]);
+        waifu2x.proc_image(image)
     }
 
-    fn get_config(&self) -> UpscalerConfig {
-        self.config
+    fn get_config(&self) -> &UpscalerConfig {
+        &self.config
     }
 }
 
 impl Upscaler for RealCuganUpscaler {
-    fn upscale_image(&self, image: DynamicImage) -> DynamicImage {
-        self.realcugan.proc_image(image)
+    fn upscale_image(&self, image: DynamicImage, noise: i32) -> DynamicImage {
+        let level = closest_noise_level(noise, self.realcugan.keys().copied());
+        self.realcugan[&level].proc_image(image)
+    }
+
+    fn get_config(&self) -> &UpscalerConfig {
+        &self.config
     }
+}
 
-    fn get_config(&self) -> UpscalerConfig {
-        self.config
+impl Upscaler for ChainUpscaler {
+    fn upscale_image(&self, image: DynamicImage, _noise: i32) -> DynamicImage {
+        self.stages.iter().fold(image, |image, stage| {
+            stage.upscale_image(image, stage.get_config().noise)
+        })
     }
-}
\ No newline at end of file
+
+    fn get_config(&self) -> &UpscalerConfig {
+        &self.config
+    }
+}